@@ -0,0 +1,17 @@
+# !/bin/bash
+
+# setup.shで作成したネットワークに、toytcpが所有するアドレス(10.0.2.1)への経路を追加する
+# host1上のTUNインタフェースtoytcp0の先にtoytcpがいる構成
+#
+# host2 --- router --- host1 --- toytcp0 --- toytcp(10.0.2.1)
+#
+# カーネルは10.0.2.1を所有していないのでRSTを返さず、iptablesでRSTを破棄する必要がない
+
+set -eux
+
+sudo ip netns exec host1 ip tuntap add dev toytcp0 mode tun
+sudo ip netns exec host1 ip addr add 10.0.2.254/24 dev toytcp0
+sudo ip netns exec host1 ip link set toytcp0 up
+sudo ip netns exec host1 sysctl -w net.ipv4.ip_forward=1
+
+sudo ip netns exec router ip route add 10.0.2.0/24 via 10.0.0.1
@@ -20,6 +20,10 @@ sudo ip netns exec host1 ip addr add 10.0.0.1/24 dev host1-veth1
 sudo ip netns exec router ip addr add 10.0.0.254/24 dev router-veth1
 sudo ip netns exec router ip addr add 10.0.1.254/24 dev router-veth2
 sudo ip netns exec host2 ip addr add 10.0.1.1/24 dev host2-veth1
+sudo ip netns exec host1 ip addr add fd00:0::1/64 dev host1-veth1 nodad
+sudo ip netns exec router ip addr add fd00:0::fe/64 dev router-veth1 nodad
+sudo ip netns exec router ip addr add fd00:1::fe/64 dev router-veth2 nodad
+sudo ip netns exec host2 ip addr add fd00:1::1/64 dev host2-veth1 nodad
 
 sudo ip netns exec host1 ip link set host1-veth1 up
 sudo ip netns exec router ip link set router-veth1 up
@@ -32,10 +36,17 @@ sudo ip netns exec host2 ip link set lo up
 sudo ip netns exec host1 ip route add 0.0.0.0/0 via 10.0.0.254
 sudo ip netns exec host2 ip route add 0.0.0.0/0 via 10.0.1.254
 sudo ip netns exec router sysctl -w net.ipv4.ip_forward=1
+sudo ip netns exec host1 ip -6 route add default via fd00:0::fe
+sudo ip netns exec host2 ip -6 route add default via fd00:1::fe
+sudo ip netns exec router sysctl -w net.ipv6.conf.all.forwarding=1
 
 # drop RST
+# カーネルは自身のアドレス宛てでソケットのないセグメントにRSTを返し、RAWソケット(PnetDevice)を使うtoytcpの接続を切断してしまう
+# TunDeviceを使う場合はtoytcpのアドレスをカーネルが所有しないため、この設定は不要(setup-tun.sh参照)
 sudo ip netns exec host1 sudo iptables -A OUTPUT -p tcp --tcp-flags RST RST -j DROP
 sudo ip netns exec host2 sudo iptables -A OUTPUT -p tcp --tcp-flags RST RST -j DROP
+sudo ip netns exec host1 sudo ip6tables -A OUTPUT -p tcp --tcp-flags RST RST -j DROP
+sudo ip netns exec host2 sudo ip6tables -A OUTPUT -p tcp --tcp-flags RST RST -j DROP
 
 # turn off checksum offloading
 sudo ip netns exec host2 sudo ethtool -K host2-veth1 tx off
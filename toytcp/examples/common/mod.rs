@@ -0,0 +1,41 @@
+use anyhow::Result;
+use std::sync::Arc;
+use std::{net::IpAddr, str};
+use toytcp::device::Device;
+use toytcp::tcp::TCP;
+
+// echoserverとtunechoserverで共有するエコーサーバの本体
+// 受け付けたコネクションごとにスレッドを立て、受信したデータをそのまま送り返す
+pub fn echo_server<D: Device>(tcp: Arc<TCP<D>>, local_addr: IpAddr, local_port: u16) -> Result<()> {
+    let listening_socket = tcp.listen(local_addr, local_port)?;
+    dbg!("listening...");
+    loop {
+        let connected_socket = tcp.accept(listening_socket)?;
+        dbg!("accepted!", connected_socket.1, connected_socket.3);
+        let cloned_tcp = tcp.clone();
+
+        std::thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            loop {
+                let nbytes = match cloned_tcp.recv(connected_socket, &mut buffer) {
+                    Ok(nbytes) => nbytes,
+                    Err(error) => {
+                        // 相手がRSTで接続を中断した
+                        dbg!(error);
+                        let _ = cloned_tcp.close(connected_socket);
+                        return;
+                    }
+                };
+                if nbytes == 0 {
+                    dbg!("closing connection...");
+                    cloned_tcp.close(connected_socket).unwrap();
+                    return;
+                }
+                print!("> {}", str::from_utf8(&buffer[..nbytes]).unwrap());
+                cloned_tcp
+                    .send(connected_socket, &buffer[..nbytes])
+                    .unwrap();
+            }
+        });
+    }
+}
@@ -0,0 +1,16 @@
+mod common;
+
+use anyhow::Result;
+use std::{env, net::IpAddr};
+use toytcp::tcp::TCP;
+use toytcp::tun::TunDevice;
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let ifname: &str = &args[1];
+    let addr: IpAddr = args[2].parse()?;
+    let port: u16 = args[3].parse()?;
+    let tcp = TCP::with_device(TunDevice::new(ifname, addr)?);
+    common::echo_server(tcp, addr, port)?;
+    Ok(())
+}
@@ -0,0 +1,625 @@
+use crate::congestion::{AckSample, CongestionControl, CongestionInfo, CongestionWindow};
+use rand::Rng;
+use std::cmp;
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+// BBR (v1)
+// https://datatracker.ietf.org/doc/html/draft-cardwell-iccrg-bbr-congestion-control-00
+//
+// ロスではなく、配送レートとRTTの測定から経路のボトルネック帯域(BtlBw)と往復伝搬遅延(RTprop)を求め、
+// その積(BDP)だけのデータを、BtlBwに合わせた間隔で送信する
+//   pacing_rate = pacing_gain * BtlBw
+//   cwnd = cwnd_gain * BtlBw * RTprop
+// BtlBwは直近10ラウンドの配送レートの最大値、RTpropは直近10秒のRTTの最小値とする
+// 以下のモードを遷移しながら、それぞれを測定し直す
+//   STARTUP:   BtlBwが増えなくなるまで、送信レートを毎ラウンド約2倍にする
+//   DRAIN:     STARTUPでボトルネックに溜めたキューを捌く
+//   PROBE_BW:  送信レートを1.25倍、0.75倍、1倍(6ラウンド)と変えながら、帯域の増加を探る
+//   PROBE_RTT: RTpropが10秒間更新されなければ、cwndを4セグメントに絞ってキューを空にし、RTTを測り直す
+
+// 送信レートを毎ラウンド2倍にできる最小のゲイン(2/ln2)
+const HIGH_GAIN: f64 = 2.885;
+const PACING_GAIN_CYCLE: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+const PROBE_BW_CWND_GAIN: f64 = 2.0;
+const BTL_BW_FILTER_LEN: u64 = 10; // ラウンド
+const RTPROP_FILTER_LEN: Duration = Duration::from_secs(10);
+const PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+// cwndの下限(セグメント数)。遅延ACKがあってもACKが返ってくるようにする
+const MIN_PIPE_CWND: usize = 4;
+// BtlBwが3ラウンド続けて25%以上増えなければ、帯域を使い切ったとみなす
+const FULL_BW_THRESHOLD: f64 = 1.25;
+const FULL_BW_COUNT: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BbrMode {
+    Startup,
+    Drain,
+    ProbeBw,
+    ProbeRtt,
+}
+
+#[derive(Debug)]
+pub struct Bbr {
+    mode: BbrMode,
+    pacing_gain: f64,
+    cwnd_gain: f64,
+    pacing_rate: Option<u64>, // BtlBwを測定するまではペーシングしない
+
+    // BtlBw。ラウンドごとの配送レートの最大値を保持する
+    btl_bw_filter: VecDeque<(u64, u64)>,
+    // RTpropと、それを測定した時刻
+    rtprop: Option<Duration>,
+    rtprop_stamp: Option<SystemTime>,
+    rtprop_expired: bool,
+
+    // ラウンドの開始時点でACKされていたバイト数。この時点以降に送信したデータがACKされたらラウンドを進める
+    // ACKされたバイト数は配送レートの測定値(RateSample)から求め、独自には数えない
+    next_round_delivered: u64,
+    round_count: u64,
+    round_start: bool,
+
+    // STARTUPを終える判定
+    filled_pipe: bool,
+    full_bw: u64,
+    full_bw_count: u32,
+
+    // PROBE_BW
+    cycle_index: usize,
+    cycle_stamp: Option<SystemTime>,
+    loss_in_cycle: bool,
+
+    // PROBE_RTT
+    probe_rtt_done_stamp: Option<SystemTime>,
+    probe_rtt_round_done: bool,
+
+    // ロスの回復やPROBE_RTTの前のcwnd。終わった後に戻す
+    prior_cwnd: usize,
+    in_recovery: bool,
+}
+
+// 統計情報として公開するBBRの状態
+#[derive(Clone, Debug)]
+pub struct BbrInfo {
+    pub mode: BbrMode,
+    pub btl_bw: u64, // バイト/秒
+    pub rtprop: Option<Duration>,
+    pub pacing_gain: f64,
+    pub cwnd_gain: f64,
+    pub filled_pipe: bool,
+    pub round_count: u64,
+    pub cycle_index: usize,
+}
+
+impl Bbr {
+    pub fn new() -> Self {
+        Self {
+            mode: BbrMode::Startup,
+            pacing_gain: HIGH_GAIN,
+            cwnd_gain: HIGH_GAIN,
+            pacing_rate: None,
+            btl_bw_filter: VecDeque::new(),
+            rtprop: None,
+            rtprop_stamp: None,
+            rtprop_expired: false,
+            next_round_delivered: 0,
+            round_count: 0,
+            round_start: false,
+            filled_pipe: false,
+            full_bw: 0,
+            full_bw_count: 0,
+            cycle_index: 0,
+            cycle_stamp: None,
+            loss_in_cycle: false,
+            probe_rtt_done_stamp: None,
+            probe_rtt_round_done: false,
+            prior_cwnd: 0,
+            in_recovery: false,
+        }
+    }
+
+    fn btl_bw(&self) -> u64 {
+        self.btl_bw_filter
+            .iter()
+            .map(|&(_, bw)| bw)
+            .max()
+            .unwrap_or(0)
+    }
+
+    // BDPにgainを掛けた送信中のデータ量。キューに溜まるACKの分として3セグメントを加える
+    // BtlBwかRTpropをまだ測定していなければNone
+    fn inflight(&self, window: &CongestionWindow, gain: f64) -> Option<usize> {
+        let rtprop = self.rtprop?;
+        let btl_bw = self.btl_bw();
+        if btl_bw == 0 {
+            return None;
+        }
+        let bdp = btl_bw as f64 * rtprop.as_secs_f64();
+        Some((gain * bdp) as usize + 3 * window.mss)
+    }
+
+    // ラウンドを進め、BtlBwを更新する
+    fn update_btl_bw(&mut self, sample: &AckSample) {
+        self.round_start = false;
+        let rate = match sample.rate {
+            Some(rate) => rate,
+            None => return,
+        };
+        if rate.prior_delivered >= self.next_round_delivered {
+            self.next_round_delivered = rate.prior_delivered + rate.delivered;
+            self.round_count += 1;
+            self.round_start = true;
+        }
+        let delivery_rate = match rate.delivery_rate {
+            Some(delivery_rate) => delivery_rate,
+            None => return,
+        };
+        // アプリケーションに制限された測定値は、帯域を過小に見積もるので、これまでの最大値を超える場合だけ使う
+        if rate.is_app_limited && delivery_rate < self.btl_bw() {
+            return;
+        }
+        match self.btl_bw_filter.back_mut() {
+            Some((round, bw)) if *round == self.round_count => *bw = cmp::max(*bw, delivery_rate),
+            _ => self
+                .btl_bw_filter
+                .push_back((self.round_count, delivery_rate)),
+        }
+        while let Some(&(round, _)) = self.btl_bw_filter.front() {
+            if round + BTL_BW_FILTER_LEN > self.round_count {
+                break;
+            }
+            self.btl_bw_filter.pop_front();
+        }
+    }
+
+    fn update_rtprop(&mut self, sample: &AckSample) {
+        self.rtprop_expired = self
+            .rtprop_stamp
+            .is_some_and(|stamp| sample.now > stamp + RTPROP_FILTER_LEN);
+        if let Some(rtt) = sample.rtt {
+            if self.rtprop.is_none_or(|rtprop| rtt <= rtprop) || self.rtprop_expired {
+                self.rtprop = Some(rtt);
+                self.rtprop_stamp = Some(sample.now);
+            }
+        }
+    }
+
+    fn check_full_pipe(&mut self, sample: &AckSample) {
+        let app_limited = sample.rate.is_some_and(|rate| rate.is_app_limited);
+        if self.filled_pipe || !self.round_start || app_limited {
+            return;
+        }
+        let btl_bw = self.btl_bw();
+        if btl_bw as f64 >= self.full_bw as f64 * FULL_BW_THRESHOLD {
+            self.full_bw = btl_bw;
+            self.full_bw_count = 0;
+            return;
+        }
+        self.full_bw_count += 1;
+        if self.full_bw_count >= FULL_BW_COUNT {
+            dbg!("bbr: filled pipe", btl_bw);
+            self.filled_pipe = true;
+        }
+    }
+
+    fn check_drain(&mut self, window: &CongestionWindow, sample: &AckSample) {
+        if self.mode == BbrMode::Startup && self.filled_pipe {
+            self.mode = BbrMode::Drain;
+            self.pacing_gain = 1.0 / HIGH_GAIN;
+            self.cwnd_gain = HIGH_GAIN;
+            dbg!("bbr: drain");
+        }
+        if self.mode == BbrMode::Drain
+            && self
+                .inflight(window, 1.0)
+                .is_some_and(|bdp| sample.flight_size <= bdp)
+        {
+            self.enter_probe_bw(sample.now);
+        }
+    }
+
+    fn enter_probe_bw(&mut self, now: SystemTime) {
+        dbg!("bbr: probe bw");
+        self.mode = BbrMode::ProbeBw;
+        self.cwnd_gain = PROBE_BW_CWND_GAIN;
+        // 0.75倍以外の位相からランダムに始め、複数のフローが同時に帯域を探らないようにする
+        self.cycle_index = PACING_GAIN_CYCLE.len() - 1 - rand::thread_rng().gen_range(0..=6);
+        self.advance_cycle_phase(now);
+    }
+
+    fn advance_cycle_phase(&mut self, now: SystemTime) {
+        self.cycle_index = (self.cycle_index + 1) % PACING_GAIN_CYCLE.len();
+        self.cycle_stamp = Some(now);
+        self.pacing_gain = PACING_GAIN_CYCLE[self.cycle_index];
+        self.loss_in_cycle = false;
+    }
+
+    fn check_cycle_phase(&mut self, window: &CongestionWindow, sample: &AckSample) {
+        if self.mode != BbrMode::ProbeBw {
+            return;
+        }
+        let is_full_length = match (self.cycle_stamp, self.rtprop) {
+            (Some(stamp), Some(rtprop)) => sample.now > stamp + rtprop,
+            _ => true,
+        };
+        // ACKを処理する前の送信中のバイト数
+        let prior_inflight = sample.flight_size + sample.acked;
+        let next_phase = if self.pacing_gain > 1.0 {
+            // BDPの1.25倍を送信するか、ロスが発生するまで帯域を探る
+            is_full_length
+                && (self.loss_in_cycle
+                    || self
+                        .inflight(window, self.pacing_gain)
+                        .is_none_or(|inflight| prior_inflight >= inflight))
+        } else if self.pacing_gain < 1.0 {
+            // 探った分のキューが捌けたら早めに終える
+            is_full_length
+                || self
+                    .inflight(window, 1.0)
+                    .is_some_and(|bdp| prior_inflight <= bdp)
+        } else {
+            is_full_length
+        };
+        if next_phase {
+            self.advance_cycle_phase(sample.now);
+        }
+    }
+
+    fn check_probe_rtt(&mut self, window: &mut CongestionWindow, sample: &AckSample) {
+        if self.mode != BbrMode::ProbeRtt && self.rtprop_expired {
+            dbg!("bbr: probe rtt");
+            self.save_cwnd(window);
+            self.mode = BbrMode::ProbeRtt;
+            self.pacing_gain = 1.0;
+            self.cwnd_gain = 1.0;
+            self.probe_rtt_done_stamp = None;
+        }
+        if self.mode != BbrMode::ProbeRtt {
+            return;
+        }
+        match self.probe_rtt_done_stamp {
+            None if sample.flight_size <= MIN_PIPE_CWND * window.mss => {
+                // キューが空になった。ここから200ms以上かつ1ラウンド以上RTTを測定する
+                self.probe_rtt_done_stamp = Some(sample.now + PROBE_RTT_DURATION);
+                self.probe_rtt_round_done = false;
+                if let Some(rate) = sample.rate {
+                    self.next_round_delivered = rate.prior_delivered + rate.delivered;
+                }
+            }
+            None => {}
+            Some(done_stamp) => {
+                if self.round_start {
+                    self.probe_rtt_round_done = true;
+                }
+                if self.probe_rtt_round_done && sample.now > done_stamp {
+                    self.rtprop_stamp = Some(sample.now);
+                    self.restore_cwnd(window);
+                    if self.filled_pipe {
+                        self.enter_probe_bw(sample.now);
+                    } else {
+                        self.mode = BbrMode::Startup;
+                        self.pacing_gain = HIGH_GAIN;
+                        self.cwnd_gain = HIGH_GAIN;
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_pacing_rate(&mut self) {
+        let btl_bw = self.btl_bw();
+        if btl_bw == 0 {
+            return;
+        }
+        let rate = (self.pacing_gain * btl_bw as f64) as u64;
+        // STARTUP中は、測定値のばらつきで送信レートを下げない
+        if self.filled_pipe || self.pacing_rate.is_none_or(|current| rate > current) {
+            self.pacing_rate = Some(rate);
+        }
+    }
+
+    fn set_cwnd(&mut self, window: &mut CongestionWindow, sample: &AckSample) {
+        if self.in_recovery {
+            // ロスの回復中はパケット保存則に従い、ACKされた分だけ送信する
+            window.cwnd = cmp::max(window.cwnd, sample.flight_size + sample.acked);
+        } else {
+            match self.inflight(window, self.cwnd_gain) {
+                Some(target) if self.filled_pipe => {
+                    window.cwnd = cmp::min(window.cwnd + sample.acked, target);
+                }
+                Some(target) if window.cwnd >= target => {}
+                _ => window.cwnd += sample.acked,
+            }
+        }
+        window.cwnd = cmp::max(window.cwnd, MIN_PIPE_CWND * window.mss);
+        if self.mode == BbrMode::ProbeRtt {
+            window.cwnd = cmp::min(window.cwnd, MIN_PIPE_CWND * window.mss);
+        }
+    }
+
+    fn save_cwnd(&mut self, window: &CongestionWindow) {
+        self.prior_cwnd = if !self.in_recovery && self.mode != BbrMode::ProbeRtt {
+            window.cwnd
+        } else {
+            cmp::max(self.prior_cwnd, window.cwnd)
+        };
+    }
+
+    fn restore_cwnd(&self, window: &mut CongestionWindow) {
+        window.cwnd = cmp::max(window.cwnd, self.prior_cwnd);
+    }
+}
+
+impl Default for Bbr {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CongestionControl for Bbr {
+    fn name(&self) -> &'static str {
+        "bbr"
+    }
+
+    fn new_instance(&self) -> Box<dyn CongestionControl> {
+        Box::new(Self::new())
+    }
+
+    fn on_ack(&mut self, window: &mut CongestionWindow, sample: &AckSample) {
+        // モデルの更新
+        self.update_btl_bw(sample);
+        self.check_cycle_phase(window, sample);
+        self.check_full_pipe(sample);
+        self.check_drain(window, sample);
+        self.update_rtprop(sample);
+        self.check_probe_rtt(window, sample);
+        // 送信レートとcwndの更新
+        self.set_pacing_rate();
+        self.set_cwnd(window, sample);
+        dbg!(self.mode, self.btl_bw(), self.rtprop, self.pacing_rate);
+    }
+
+    fn on_duplicate_ack(&mut self, window: &mut CongestionWindow) {
+        // 相手に届いたセグメントの分だけ送信できる
+        window.cwnd += window.mss;
+    }
+
+    fn on_loss(&mut self, window: &mut CongestionWindow, flight_size: usize) {
+        // ロスでは帯域のモデルを変えず、回復が終わるまで送信中のデータ量を保つ
+        self.save_cwnd(window);
+        self.in_recovery = true;
+        self.loss_in_cycle = true;
+        window.cwnd = flight_size + window.mss;
+    }
+
+    fn on_recovery_end(&mut self, window: &mut CongestionWindow, _flight_size: usize) {
+        self.in_recovery = false;
+        self.restore_cwnd(window);
+    }
+
+    fn on_timeout(&mut self, window: &mut CongestionWindow, _flight_size: usize) {
+        self.save_cwnd(window);
+        self.in_recovery = false;
+        self.loss_in_cycle = true;
+        window.cwnd = window.mss;
+    }
+
+    fn info(&self) -> CongestionInfo {
+        CongestionInfo::Bbr(BbrInfo {
+            mode: self.mode,
+            btl_bw: self.btl_bw(),
+            rtprop: self.rtprop,
+            pacing_gain: self.pacing_gain,
+            cwnd_gain: self.cwnd_gain,
+            filled_pipe: self.filled_pipe,
+            round_count: self.round_count,
+            cycle_index: self.cycle_index,
+        })
+    }
+
+    fn pacing_rate(&self) -> Option<u64> {
+        self.pacing_rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rate::RateSample;
+
+    const MSS: usize = 1460;
+
+    // 途中で輻輳制御を切り替えた場合のように、既に多くのデータがACKされた状態から始める
+    #[test]
+    fn round_advances_once_per_flight() {
+        let mut bbr = Bbr::new();
+        let mut window = CongestionWindow::new(MSS);
+        let start = SystemTime::UNIX_EPOCH;
+        let mut delivered = 1_000_000;
+        for round in 1..=5 {
+            // ラウンドの開始時点で送信した10セグメントが順にACKされる
+            let prior_delivered = delivered;
+            for i in 0..10 {
+                delivered += MSS as u64;
+                let sample = AckSample {
+                    acked: MSS,
+                    flight_size: (9 - i) * MSS,
+                    rtt: Some(Duration::from_millis(100)),
+                    rate: Some(RateSample {
+                        delivery_rate: Some(146_000),
+                        delivered: delivered - prior_delivered,
+                        prior_delivered,
+                        interval: Duration::from_millis(100),
+                        is_app_limited: false,
+                    }),
+                    in_recovery: false,
+                    now: start + Duration::from_millis(100 * round),
+                };
+                bbr.on_ack(&mut window, &sample);
+                assert_eq!(bbr.round_count, round);
+                assert_eq!(bbr.round_start, i == 0);
+            }
+        }
+    }
+
+    // 1ラウンドごとに10セグメントを送信し、それらが順にACKされる流れ
+    struct Flow {
+        bbr: Bbr,
+        window: CongestionWindow,
+        now: SystemTime,
+        delivered: u64,
+    }
+
+    impl Flow {
+        fn new() -> Self {
+            Self {
+                bbr: Bbr::new(),
+                window: CongestionWindow::new(MSS),
+                now: SystemTime::UNIX_EPOCH,
+                delivered: 0,
+            }
+        }
+
+        // 配送レートbw、RTT rttで1ラウンド分のACKを受け取る。flight_sizeは各ACKの後に送信中のデータ量
+        fn round(&mut self, bw: u64, rtt: Duration, flight_size: usize) {
+            let prior_delivered = self.delivered;
+            for _ in 0..10 {
+                self.delivered += MSS as u64;
+                let sample = AckSample {
+                    acked: MSS,
+                    flight_size,
+                    rtt: Some(rtt),
+                    rate: Some(RateSample {
+                        delivery_rate: Some(bw),
+                        delivered: self.delivered - prior_delivered,
+                        prior_delivered,
+                        interval: rtt,
+                        is_app_limited: false,
+                    }),
+                    in_recovery: false,
+                    now: self.now,
+                };
+                self.bbr.on_ack(&mut self.window, &sample);
+            }
+            self.now += rtt;
+        }
+    }
+
+    const BW: u64 = 1_000_000;
+    const RTT: Duration = Duration::from_millis(100);
+    // BW * RTTより十分に多い
+    const LARGE_FLIGHT: usize = 200_000;
+
+    // 帯域が増えなくなったらDRAINに移り、キューを捌き終えたらPROBE_BWに移る
+    fn fill_pipe(flow: &mut Flow) {
+        // 1ラウンド目で帯域を記録し、その後3ラウンド増えなければ帯域を使い切ったとみなす
+        for _ in 0..3 {
+            flow.round(BW, RTT, LARGE_FLIGHT);
+            assert_eq!(flow.bbr.mode, BbrMode::Startup);
+            assert_eq!(flow.bbr.pacing_gain, HIGH_GAIN);
+        }
+        flow.round(BW, RTT, LARGE_FLIGHT);
+        assert!(flow.bbr.filled_pipe);
+        assert_eq!(flow.bbr.mode, BbrMode::Drain);
+        assert_eq!(flow.bbr.pacing_gain, 1.0 / HIGH_GAIN);
+        assert_eq!(flow.bbr.pacing_rate, Some((BW as f64 / HIGH_GAIN) as u64));
+
+        // 送信中のデータがBDPに3セグメントを加えた量まで減るとPROBE_BWに移る
+        flow.round(BW, RTT, 100_000 + 3 * MSS + 1);
+        assert_eq!(flow.bbr.mode, BbrMode::Drain);
+        flow.round(BW, RTT, 100_000 + 3 * MSS);
+        assert_eq!(flow.bbr.mode, BbrMode::ProbeBw);
+        assert_eq!(flow.bbr.cwnd_gain, PROBE_BW_CWND_GAIN);
+        // 送信レートを下げる段階からは始めない
+        assert_ne!(flow.bbr.pacing_gain, 0.75);
+    }
+
+    #[test]
+    fn startup_drain_probe_bw() {
+        let mut flow = Flow::new();
+        fill_pipe(&mut flow);
+    }
+
+    // 帯域が25%以上増え続ける間はSTARTUPに留まる
+    #[test]
+    fn startup_continues_while_bandwidth_grows() {
+        let mut flow = Flow::new();
+        let mut bw = BW;
+        for _ in 0..10 {
+            flow.round(bw, RTT, LARGE_FLIGHT);
+            bw = bw * 3 / 2;
+        }
+        assert!(!flow.bbr.filled_pipe);
+        assert_eq!(flow.bbr.mode, BbrMode::Startup);
+    }
+
+    #[test]
+    fn probe_rtt_entry_and_exit() {
+        let mut flow = Flow::new();
+        fill_pipe(&mut flow);
+        let rtprop_stamp = flow.bbr.rtprop_stamp.unwrap();
+
+        // RTpropより大きいRTTしか測定されないまま10秒が経つとPROBE_RTTに入る
+        let rtt = Duration::from_millis(120);
+        while flow.now <= rtprop_stamp + RTPROP_FILTER_LEN {
+            assert_eq!(flow.bbr.mode, BbrMode::ProbeBw);
+            assert_eq!(flow.bbr.rtprop, Some(RTT));
+            flow.round(BW, rtt, 100_000);
+        }
+        flow.round(BW, rtt, 100_000);
+        assert_eq!(flow.bbr.mode, BbrMode::ProbeRtt);
+        assert_eq!(flow.bbr.pacing_gain, 1.0);
+        assert_eq!(flow.window.cwnd, MIN_PIPE_CWND * MSS);
+        let prior_cwnd = flow.bbr.prior_cwnd;
+        assert!(prior_cwnd > MIN_PIPE_CWND * MSS);
+
+        // 送信中のデータが4セグメントに減るまでは、PROBE_RTTを終える時刻を決めない
+        flow.round(BW, rtt, 100_000);
+        assert_eq!(flow.bbr.probe_rtt_done_stamp, None);
+        flow.round(BW, rtt, MIN_PIPE_CWND * MSS);
+        let done_stamp = flow.bbr.probe_rtt_done_stamp.unwrap();
+
+        // 200ms経ち、ラウンドが1つ進むまで留まる
+        while flow.now <= done_stamp {
+            assert_eq!(flow.bbr.mode, BbrMode::ProbeRtt);
+            flow.round(BW, rtt, MIN_PIPE_CWND * MSS);
+        }
+        flow.round(BW, rtt, MIN_PIPE_CWND * MSS);
+        // 帯域を使い切っていたのでPROBE_BWに戻り、cwndも戻す
+        assert_eq!(flow.bbr.mode, BbrMode::ProbeBw);
+        assert!(flow.window.cwnd > MIN_PIPE_CWND * MSS);
+    }
+
+    // RTpropは10秒間更新されなければ、大きいRTTでも置き換えられる
+    #[test]
+    fn rtprop_expires() {
+        let mut flow = Flow::new();
+        flow.round(BW, RTT, LARGE_FLIGHT);
+        let rtt = Duration::from_millis(150);
+        while flow.now <= SystemTime::UNIX_EPOCH + RTPROP_FILTER_LEN {
+            flow.round(BW, rtt, LARGE_FLIGHT);
+            assert_eq!(flow.bbr.rtprop, Some(RTT));
+        }
+        flow.round(BW, rtt, LARGE_FLIGHT);
+        assert_eq!(flow.bbr.rtprop, Some(rtt));
+
+        // 小さいRTTはすぐに反映される
+        let rtt = Duration::from_millis(50);
+        flow.round(BW, rtt, LARGE_FLIGHT);
+        assert_eq!(flow.bbr.rtprop, Some(rtt));
+    }
+
+    // BtlBwは直近10ラウンドの最大値なので、大きい配送レートも10ラウンド後には使われなくなる
+    #[test]
+    fn btl_bw_expires() {
+        let mut flow = Flow::new();
+        flow.round(2 * BW, RTT, LARGE_FLIGHT);
+        assert_eq!(flow.bbr.btl_bw(), 2 * BW);
+        for _ in 0..(BTL_BW_FILTER_LEN - 1) {
+            flow.round(BW, RTT, LARGE_FLIGHT);
+            assert_eq!(flow.bbr.btl_bw(), 2 * BW);
+        }
+        flow.round(BW, RTT, LARGE_FLIGHT);
+        assert_eq!(flow.bbr.btl_bw(), BW);
+    }
+}
@@ -0,0 +1,69 @@
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// タイマーや再送処理が参照する時計
+// 実装を差し替えることで、テストでは実時間を待たずに時間を進めることができる
+pub trait Clock: Send + Sync + 'static {
+    // 現在時刻を返す
+    fn now(&self) -> SystemTime;
+
+    // 指定した時間だけスレッドをブロックする
+    fn sleep(&self, duration: Duration);
+}
+
+// 実時間に従うデフォルトの時計
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+// advanceを呼び出した時にだけ進む仮想的な時計
+// sleepしているスレッドは、時計が目標の時刻まで進められるまでブロックする
+pub struct VirtualClock {
+    now: Mutex<SystemTime>,
+    condvar: Condvar,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self {
+            now: Mutex::new(SystemTime::UNIX_EPOCH),
+            condvar: Condvar::new(),
+        }
+    }
+
+    // 時計を指定した時間だけ進め、sleepしているスレッドを起こす
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now += duration;
+        self.condvar.notify_all();
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        let deadline = *now + duration;
+        while *now < deadline {
+            now = self.condvar.wait(now).unwrap();
+        }
+    }
+}
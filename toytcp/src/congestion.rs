@@ -0,0 +1,176 @@
+use crate::bbr::BbrInfo;
+use crate::cubic::CubicInfo;
+use crate::rate::RateSample;
+use std::cmp;
+use std::time::{Duration, SystemTime};
+
+// 輻輳制御
+// ネットワークの混雑に応じて、ACKを待たずに送信できるデータ量(輻輳ウィンドウ、cwnd)を調整する
+// 実際に送信できる量は、cwndと相手の受信ウィンドウ(rwnd)の小さい方から送信中のデータ量を引いたものになる
+// https://datatracker.ietf.org/doc/html/rfc5681
+
+// ソケットごとの輻輳ウィンドウの状態。アルゴリズムを切り替えても引き継がれる
+#[derive(Clone, Debug)]
+pub struct CongestionWindow {
+    pub cwnd: usize,     // 輻輳ウィンドウ(バイト)
+    pub ssthresh: usize, // スロースタートとする閾値(バイト)
+    pub mss: usize,      // 送信するセグメントの最大サイズ
+}
+
+impl CongestionWindow {
+    pub fn new(mss: usize) -> Self {
+        Self {
+            // 初期ウィンドウ(RFC 5681 3.1)
+            cwnd: if mss > 2190 {
+                2 * mss
+            } else if mss > 1095 {
+                3 * mss
+            } else {
+                4 * mss
+            },
+            // 初期値は任意に大きくてよい
+            ssthresh: usize::MAX,
+            mss,
+        }
+    }
+
+    pub fn in_slow_start(&self) -> bool {
+        self.cwnd < self.ssthresh
+    }
+}
+
+// 新しいデータに対するACKを受信した時に、輻輳制御アルゴリズムへ渡す情報
+#[derive(Clone, Debug)]
+pub struct AckSample {
+    pub acked: usize,             // 今回のACKで確認されたバイト数
+    pub flight_size: usize,       // ACKを処理した後の送信中のバイト数
+    pub rtt: Option<Duration>,    // 今回のACKで測定したRTT。再送したセグメントでは測定しない
+    pub rate: Option<RateSample>, // 今回のACKで測定した配送レート
+    pub in_recovery: bool,        // 高速リカバリ中に受信したACKか(部分ACKやリカバリを終えるACK)
+    pub now: SystemTime,
+}
+
+// 輻輳制御アルゴリズム
+// 各メソッドはACKやロスの検出に応じて呼び出され、CongestionWindowを更新する
+pub trait CongestionControl: Send + Sync {
+    // ログに表示するアルゴリズムの名前
+    fn name(&self) -> &'static str;
+
+    // 同じアルゴリズムのインスタンスを初期状態で生成する。新しいソケットへ設定を引き継ぐのに使う
+    fn new_instance(&self) -> Box<dyn CongestionControl>;
+
+    // 新しいデータがACKされた
+    fn on_ack(&mut self, window: &mut CongestionWindow, sample: &AckSample);
+
+    // 高速リカバリ中に重複ACKを受信した。相手にセグメントが1つ届いたことを意味する
+    fn on_duplicate_ack(&mut self, window: &mut CongestionWindow);
+
+    // 3つの重複ACKでロスを検出し、高速再送を行って高速リカバリに入る
+    fn on_loss(&mut self, window: &mut CongestionWindow, flight_size: usize);
+
+    // 高速リカバリの開始時に送信済みだったデータが全てACKされ、リカバリを終える
+    fn on_recovery_end(&mut self, window: &mut CongestionWindow, flight_size: usize);
+
+    // 再送タイムアウトが発生した
+    fn on_timeout(&mut self, window: &mut CongestionWindow, flight_size: usize);
+
+    // 統計情報として公開するアルゴリズム固有の状態
+    fn info(&self) -> CongestionInfo {
+        CongestionInfo::None
+    }
+
+    // 送信レート(バイト/秒)。Noneならペーシングせず、ウィンドウが空き次第送信する
+    fn pacing_rate(&self) -> Option<u64> {
+        None
+    }
+}
+
+// アルゴリズム固有の状態。LinuxのTCP_CC_INFOに相当する
+#[derive(Clone, Debug)]
+pub enum CongestionInfo {
+    None,
+    Cubic(CubicInfo),
+    Bbr(BbrInfo),
+}
+
+// 高速リカバリ中の部分ACK。ACKされた分だけcwndを縮め、1MSS以上ACKされていれば再送するセグメントの分を加える
+// https://datatracker.ietf.org/doc/html/rfc6582#section-3.2
+pub fn deflate_on_partial_ack(window: &mut CongestionWindow, acked: usize) {
+    window.cwnd = window.cwnd.saturating_sub(acked);
+    if acked >= window.mss {
+        window.cwnd += window.mss;
+    }
+    window.cwnd = cmp::max(window.cwnd, window.mss);
+}
+
+// 高速リカバリを終える時に、膨らませたcwndを戻す。送信中のデータが少なければ、一度に大量に送信しないよう抑える
+pub fn deflate_on_recovery_end(window: &mut CongestionWindow, flight_size: usize) {
+    window.cwnd = cmp::min(
+        window.ssthresh,
+        cmp::max(flight_size, window.mss) + window.mss,
+    );
+}
+
+// NewReno
+// https://datatracker.ietf.org/doc/html/rfc5681
+// https://datatracker.ietf.org/doc/html/rfc6582
+#[derive(Debug, Default)]
+pub struct NewReno {
+    // 輻輳回避中に、cwndを1MSS増やすまでにACKされたバイト数
+    bytes_acked: usize,
+}
+
+impl NewReno {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CongestionControl for NewReno {
+    fn name(&self) -> &'static str {
+        "newreno"
+    }
+
+    fn new_instance(&self) -> Box<dyn CongestionControl> {
+        Box::new(Self::new())
+    }
+
+    fn on_ack(&mut self, window: &mut CongestionWindow, sample: &AckSample) {
+        if sample.in_recovery {
+            deflate_on_partial_ack(window, sample.acked);
+        } else if window.in_slow_start() {
+            // スロースタート。ACKごとに最大1MSSずつ増やす
+            window.cwnd += cmp::min(sample.acked, window.mss);
+        } else {
+            // 輻輳回避。1RTTあたり1MSSずつ増やす
+            self.bytes_acked += sample.acked;
+            if self.bytes_acked >= window.cwnd {
+                self.bytes_acked -= window.cwnd;
+                window.cwnd += window.mss;
+            }
+        }
+    }
+
+    fn on_duplicate_ack(&mut self, window: &mut CongestionWindow) {
+        // 相手に届いたセグメントの分だけcwndを膨らませ、新しいセグメントを送信できるようにする
+        window.cwnd += window.mss;
+    }
+
+    fn on_loss(&mut self, window: &mut CongestionWindow, flight_size: usize) {
+        window.ssthresh = cmp::max(flight_size / 2, 2 * window.mss);
+        // 重複ACKを返した3つのセグメントはネットワークから抜けている
+        window.cwnd = window.ssthresh + 3 * window.mss;
+        self.bytes_acked = 0;
+    }
+
+    fn on_recovery_end(&mut self, window: &mut CongestionWindow, flight_size: usize) {
+        deflate_on_recovery_end(window, flight_size);
+    }
+
+    fn on_timeout(&mut self, window: &mut CongestionWindow, flight_size: usize) {
+        window.ssthresh = cmp::max(flight_size / 2, 2 * window.mss);
+        // 1セグメントからスロースタートをやり直す
+        window.cwnd = window.mss;
+        self.bytes_acked = 0;
+    }
+}
@@ -0,0 +1,448 @@
+use crate::congestion::{self, AckSample, CongestionControl, CongestionInfo, CongestionWindow};
+use std::cmp;
+use std::time::{Duration, SystemTime};
+
+// CUBIC
+// https://datatracker.ietf.org/doc/html/rfc8312
+//
+// 直前にロスが発生した時のウィンドウ(W_max)を基準に、ロスからの経過時間の3次関数でcwndを増やす
+//   W_cubic(t) = C * (t - K)^3 + W_max
+//   K = cubic_root(W_max * (1 - beta_cubic) / C)
+// W_maxの手前では増加を緩め、超えた後は再び速く増やす。増加量がRTTに依存しないので、
+// RTTの大きい経路でも帯域を使い切れる。ウィンドウはセグメント数で計算する
+//
+// スロースタートはHyStartにより、ロスが発生する前にACKの間隔や遅延の増加から終了する
+// https://doi.org/10.1016/j.comnet.2011.01.014
+
+const C: f64 = 0.4;
+const BETA_CUBIC: f64 = 0.7;
+
+// HyStartはcwndがこのセグメント数以上の時だけ働かせる
+const HYSTART_LOW_WINDOW: f64 = 16.0;
+// 1ラウンドの最小RTTを求めるのに使うRTTの数
+const HYSTART_MIN_SAMPLES: u32 = 8;
+// この間隔以内で続けて届いたACKを一連のACKトレインとみなす
+const HYSTART_ACK_DELTA: Duration = Duration::from_millis(2);
+// 遅延の増加とみなす閾値の範囲
+const HYSTART_DELAY_MIN: Duration = Duration::from_millis(4);
+const HYSTART_DELAY_MAX: Duration = Duration::from_millis(16);
+
+#[derive(Debug, Default)]
+pub struct Cubic {
+    w_max: f64,                      // 直前のロス時のcwnd
+    w_last_max: f64,                 // その前のロス時のW_max。fast convergenceに使う
+    k: f64,                          // 3次関数がorigin_pointに戻るまでの時間(秒)
+    origin_point: f64,               // 3次関数の中心となるcwnd
+    epoch_start: Option<SystemTime>, // 現在の輻輳回避を始めた時刻
+    w_est: f64,                      // 同じ期間にRenoが到達するcwnd
+    cwnd_carry: f64,                 // まだcwndに加えていない1バイト未満の増加分
+    min_rtt: Option<Duration>,       // これまでに測定した最小のRTT
+    hystart: HyStart,
+}
+
+// 統計情報として公開するCUBICの状態。ウィンドウはセグメント数
+#[derive(Clone, Debug)]
+pub struct CubicInfo {
+    pub w_max: f64,
+    pub w_last_max: f64,
+    pub k: Duration,
+    pub origin_point: f64,
+    pub w_est: f64,
+    pub epoch_start: Option<SystemTime>,
+    pub min_rtt: Option<Duration>,
+    pub hystart_exit: Option<HyStartExit>, // HyStartがスロースタートを終えた理由
+}
+
+// HyStartがスロースタートを終えた理由
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HyStartExit {
+    AckTrain, // 1ラウンドのACKトレインが最小RTTの半分に達し、帯域を使い切ったとみなした
+    Delay,    // RTTが前のラウンドより増え、キューが溜まり始めたとみなした
+}
+
+// HyStartの状態。送信中のデータが全てACKされるまでを1ラウンドとする
+#[derive(Debug, Default)]
+struct HyStart {
+    round_start: Option<SystemTime>,
+    round_remaining: usize, // ラウンドを終えるまでにACKされる必要があるバイト数
+    last_ack: Option<SystemTime>,
+    samples: u32,
+    current_round_min_rtt: Option<Duration>,
+    last_round_min_rtt: Option<Duration>,
+    exit: Option<HyStartExit>,
+}
+
+impl HyStart {
+    // ACKを受信するたびに呼び出し、スロースタートを終えるべきであればその理由を返す
+    fn update(
+        &mut self,
+        cwnd: f64,
+        sample: &AckSample,
+        min_rtt: Option<Duration>,
+    ) -> Option<HyStartExit> {
+        let now = sample.now;
+        self.round_remaining = self.round_remaining.saturating_sub(sample.acked);
+        if self.round_start.is_none() || self.round_remaining == 0 {
+            self.round_start = Some(now);
+            self.round_remaining = sample.flight_size;
+            self.last_ack = Some(now);
+            self.last_round_min_rtt = self.current_round_min_rtt.or(self.last_round_min_rtt);
+            self.current_round_min_rtt = None;
+            self.samples = 0;
+        }
+        if cwnd < HYSTART_LOW_WINDOW {
+            return None;
+        }
+
+        // ACKトレイン
+        let elapsed = |t: Option<SystemTime>| now.duration_since(t?).ok();
+        if let (Some(gap), Some(min_rtt)) = (elapsed(self.last_ack), min_rtt) {
+            if gap <= HYSTART_ACK_DELTA {
+                self.last_ack = Some(now);
+                if elapsed(self.round_start).is_some_and(|train| train >= min_rtt / 2) {
+                    return Some(HyStartExit::AckTrain);
+                }
+            }
+        }
+
+        // 遅延の増加
+        if let Some(rtt) = sample.rtt {
+            if self.samples < HYSTART_MIN_SAMPLES {
+                self.samples += 1;
+                self.current_round_min_rtt =
+                    Some(self.current_round_min_rtt.map_or(rtt, |r| cmp::min(r, rtt)));
+            }
+            if let (HYSTART_MIN_SAMPLES, Some(current), Some(last)) = (
+                self.samples,
+                self.current_round_min_rtt,
+                self.last_round_min_rtt,
+            ) {
+                let eta = (last / 8).clamp(HYSTART_DELAY_MIN, HYSTART_DELAY_MAX);
+                if current >= last + eta {
+                    return Some(HyStartExit::Delay);
+                }
+            }
+        }
+        None
+    }
+
+    // タイムアウト後のスロースタートで、最初のラウンドから測定し直す
+    fn reset(&mut self) {
+        *self = Self {
+            exit: self.exit,
+            ..Self::default()
+        };
+    }
+}
+
+impl Cubic {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // ロスを検出した時にW_maxを更新し、ssthreshを下げる
+    fn reduce(&mut self, window: &mut CongestionWindow) {
+        let cwnd = window.cwnd as f64 / window.mss as f64;
+        // fast convergence
+        // 前回のロスよりも小さいウィンドウでロスした場合は、新しいフローに帯域を譲るためW_maxをさらに下げる
+        if cwnd < self.w_last_max {
+            self.w_last_max = cwnd;
+            self.w_max = cwnd * (1.0 + BETA_CUBIC) / 2.0;
+        } else {
+            self.w_last_max = cwnd;
+            self.w_max = cwnd;
+        }
+        self.epoch_start = None;
+        self.cwnd_carry = 0.0;
+        window.ssthresh = cmp::max((window.cwnd as f64 * BETA_CUBIC) as usize, 2 * window.mss);
+    }
+
+    // 輻輳回避。W_cubic(t+RTT)を目標にcwndを増やす
+    fn congestion_avoidance(&mut self, window: &mut CongestionWindow, sample: &AckSample) {
+        let mss = window.mss as f64;
+        let cwnd = window.cwnd as f64 / mss;
+        let epoch_start = match self.epoch_start {
+            Some(epoch_start) => epoch_start,
+            None => {
+                if cwnd < self.w_max {
+                    self.k = ((self.w_max - cwnd) / C).cbrt();
+                    self.origin_point = self.w_max;
+                } else {
+                    self.k = 0.0;
+                    self.origin_point = cwnd;
+                }
+                self.w_est = cwnd;
+                self.epoch_start = Some(sample.now);
+                sample.now
+            }
+        };
+        let t = sample
+            .now
+            .duration_since(epoch_start)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let rtt = self.min_rtt.unwrap_or_default().as_secs_f64();
+        let w_cubic = |t: f64| C * (t - self.k).powi(3) + self.origin_point;
+
+        // TCPフレンドリ領域。Renoより遅く増えることがないよう、Renoと同じ増え方をするW_estを追う
+        let acked = sample.acked as f64 / mss;
+        self.w_est += 3.0 * (1.0 - BETA_CUBIC) / (1.0 + BETA_CUBIC) * acked / cwnd;
+        let next = if w_cubic(t) < self.w_est {
+            self.w_est
+        } else {
+            // 1RTTでcwndの1.5倍を超えて増やさない
+            let target = w_cubic(t + rtt).min(cwnd * 1.5);
+            if target > cwnd {
+                cwnd + (target - cwnd) / cwnd * acked
+            } else {
+                cwnd
+            }
+        };
+
+        let increase = (next - cwnd) * mss + self.cwnd_carry;
+        if increase > 0.0 {
+            window.cwnd += increase as usize;
+            self.cwnd_carry = increase.fract();
+        }
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn name(&self) -> &'static str {
+        "cubic"
+    }
+
+    fn new_instance(&self) -> Box<dyn CongestionControl> {
+        Box::new(Self::new())
+    }
+
+    fn on_ack(&mut self, window: &mut CongestionWindow, sample: &AckSample) {
+        if let Some(rtt) = sample.rtt {
+            self.min_rtt = Some(self.min_rtt.map_or(rtt, |r| cmp::min(r, rtt)));
+        }
+        if sample.in_recovery {
+            congestion::deflate_on_partial_ack(window, sample.acked);
+        } else if window.in_slow_start() {
+            let cwnd = window.cwnd as f64 / window.mss as f64;
+            if let Some(exit) = self.hystart.update(cwnd, sample, self.min_rtt) {
+                dbg!("hystart: exit slow start", exit, window.cwnd);
+                self.hystart.exit = Some(exit);
+                window.ssthresh = window.cwnd;
+            } else {
+                window.cwnd += cmp::min(sample.acked, window.mss);
+            }
+        } else {
+            self.congestion_avoidance(window, sample);
+        }
+    }
+
+    fn on_duplicate_ack(&mut self, window: &mut CongestionWindow) {
+        window.cwnd += window.mss;
+    }
+
+    fn on_loss(&mut self, window: &mut CongestionWindow, _flight_size: usize) {
+        self.reduce(window);
+        // 重複ACKを返した3つのセグメントはネットワークから抜けている
+        window.cwnd = window.ssthresh + 3 * window.mss;
+    }
+
+    fn on_recovery_end(&mut self, window: &mut CongestionWindow, flight_size: usize) {
+        congestion::deflate_on_recovery_end(window, flight_size);
+    }
+
+    fn on_timeout(&mut self, window: &mut CongestionWindow, _flight_size: usize) {
+        self.reduce(window);
+        window.cwnd = window.mss;
+        self.hystart.reset();
+    }
+
+    fn info(&self) -> CongestionInfo {
+        CongestionInfo::Cubic(CubicInfo {
+            w_max: self.w_max,
+            w_last_max: self.w_last_max,
+            k: Duration::from_secs_f64(self.k),
+            origin_point: self.origin_point,
+            w_est: self.w_est,
+            epoch_start: self.epoch_start,
+            min_rtt: self.min_rtt,
+            hystart_exit: self.hystart.exit,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, VirtualClock};
+
+    const MSS: usize = 1460;
+    const RTT: Duration = Duration::from_millis(100);
+
+    fn ack(clock: &VirtualClock, window: &CongestionWindow) -> AckSample {
+        AckSample {
+            acked: MSS,
+            flight_size: window.cwnd,
+            rtt: Some(RTT),
+            rate: None,
+            in_recovery: false,
+            now: clock.now(),
+        }
+    }
+
+    fn info(cubic: &Cubic) -> CubicInfo {
+        match cubic.info() {
+            CongestionInfo::Cubic(info) => info,
+            info => panic!("unexpected info {:?}", info),
+        }
+    }
+
+    // 1RTTごとにcwnd分のACKを受信しながら、時計をRTTずつ進める
+    fn run_rounds(
+        cubic: &mut Cubic,
+        window: &mut CongestionWindow,
+        clock: &VirtualClock,
+        rounds: u32,
+    ) {
+        for _ in 0..rounds {
+            for _ in 0..window.cwnd / MSS {
+                let sample = ack(clock, window);
+                cubic.on_ack(window, &sample);
+            }
+            clock.advance(RTT);
+        }
+    }
+
+    #[test]
+    fn window_grows_along_cubic_function() {
+        let clock = VirtualClock::new();
+        let mut cubic = Cubic::new();
+        let mut window = CongestionWindow::new(MSS);
+        window.cwnd = 100 * MSS;
+        window.ssthresh = 100 * MSS;
+
+        // ロスでW_maxを記録し、cwndをbeta_cubic倍にする
+        let flight_size = window.cwnd;
+        cubic.on_loss(&mut window, flight_size);
+        assert_eq!(window.ssthresh, 70 * MSS);
+        window.cwnd = window.ssthresh;
+        let sample = ack(&clock, &window);
+        cubic.on_ack(&mut window, &sample);
+        let state = info(&cubic);
+        assert_eq!(state.w_max, 100.0);
+        assert_eq!(state.epoch_start, Some(clock.now()));
+        // K = cubic_root(W_max * (1 - beta_cubic) / C) = cubic_root(75)
+        assert!((state.k.as_secs_f64() - 75f64.cbrt()).abs() < 1e-6);
+
+        // Kまでは上に凸の曲線でW_maxに近づき、その手前では増加が緩やかになる
+        run_rounds(&mut cubic, &mut window, &clock, 10);
+        let early = window.cwnd / MSS;
+        run_rounds(&mut cubic, &mut window, &clock, 32);
+        let near_k = window.cwnd / MSS;
+        assert!(early > 70 && early < near_k, "{} {}", early, near_k);
+        assert!((95..=101).contains(&near_k), "{}", near_k);
+        run_rounds(&mut cubic, &mut window, &clock, 10);
+        assert!(window.cwnd / MSS - near_k <= 2, "{}", window.cwnd / MSS);
+
+        // Kを過ぎるとW_maxを超えて、再び速く増えていく
+        run_rounds(&mut cubic, &mut window, &clock, 30);
+        let t = clock
+            .now()
+            .duration_since(state.epoch_start.unwrap())
+            .unwrap();
+        let w_cubic = C * (t.as_secs_f64() - state.k.as_secs_f64()).powi(3) + 100.0;
+        let cwnd = window.cwnd as f64 / MSS as f64;
+        assert!(cwnd > 110.0, "{}", cwnd);
+        assert!(
+            (cwnd - w_cubic).abs() < w_cubic * 0.1,
+            "{} {}",
+            cwnd,
+            w_cubic
+        );
+    }
+
+    #[test]
+    fn fast_convergence_lowers_w_max() {
+        let mut cubic = Cubic::new();
+        let mut window = CongestionWindow::new(MSS);
+        window.cwnd = 100 * MSS;
+        let flight_size = window.cwnd;
+        cubic.on_loss(&mut window, flight_size);
+        // 前回より小さいウィンドウでロスした場合は、W_maxを(1 + beta_cubic) / 2倍に下げる
+        window.cwnd = 80 * MSS;
+        let flight_size = window.cwnd;
+        cubic.on_loss(&mut window, flight_size);
+        let state = info(&cubic);
+        assert_eq!(state.w_last_max, 80.0);
+        assert!((state.w_max - 80.0 * 0.85).abs() < 1e-9);
+    }
+
+    // スロースタート中のACKを1つ受信する
+    fn slow_start_ack(
+        cubic: &mut Cubic,
+        window: &mut CongestionWindow,
+        clock: &VirtualClock,
+        rtt: Duration,
+        flight_size: usize,
+    ) {
+        let sample = AckSample {
+            acked: MSS,
+            flight_size,
+            rtt: Some(rtt),
+            rate: None,
+            in_recovery: false,
+            now: clock.now(),
+        };
+        cubic.on_ack(window, &sample);
+    }
+
+    #[test]
+    fn hystart_exits_on_ack_train() {
+        let clock = VirtualClock::new();
+        let mut cubic = Cubic::new();
+        let mut window = CongestionWindow::new(MSS);
+        window.cwnd = 20 * MSS;
+        let rtt = Duration::from_millis(10);
+
+        // 1ms間隔で届くACKの列が最小RTTの半分(5ms)に達したら、帯域を使い切ったとみなす
+        for i in 0..5 {
+            slow_start_ack(&mut cubic, &mut window, &clock, rtt, 100 * MSS);
+            assert_eq!(info(&cubic).hystart_exit, None, "{}", i);
+            assert!(window.in_slow_start());
+            clock.advance(Duration::from_millis(1));
+        }
+        let cwnd = window.cwnd;
+        slow_start_ack(&mut cubic, &mut window, &clock, rtt, 100 * MSS);
+        assert_eq!(info(&cubic).hystart_exit, Some(HyStartExit::AckTrain));
+        assert_eq!(window.cwnd, cwnd);
+        assert_eq!(window.ssthresh, cwnd);
+        assert!(!window.in_slow_start());
+    }
+
+    #[test]
+    fn hystart_exits_on_delay_increase() {
+        let clock = VirtualClock::new();
+        let mut cubic = Cubic::new();
+        let mut window = CongestionWindow::new(MSS);
+        window.cwnd = 20 * MSS;
+
+        // ACKトレインとみなされないよう、ACKの間隔を空ける
+        // 送信中のデータは8セグメントなので、8個のACKで1ラウンドになる
+        // 1ラウンド目はRTTが100ms、2ラウンド目はキューが溜まり120msに増える
+        let mut exit = None;
+        for i in 0..30 {
+            let rtt = Duration::from_millis(if i < 8 { 100 } else { 120 });
+            let cwnd = window.cwnd;
+            slow_start_ack(&mut cubic, &mut window, &clock, rtt, 8 * MSS);
+            if let Some(reason) = info(&cubic).hystart_exit {
+                exit = Some((i, reason, cwnd));
+                break;
+            }
+            clock.advance(Duration::from_millis(5));
+        }
+        // 2ラウンド目の8個目のRTTで、前のラウンドより閾値(100ms / 8)以上増えたと判断する
+        let (i, reason, cwnd) = exit.unwrap();
+        assert_eq!((i, reason), (15, HyStartExit::Delay));
+        assert_eq!(window.cwnd, cwnd);
+        assert_eq!(window.ssthresh, cwnd);
+    }
+}
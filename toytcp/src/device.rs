@@ -0,0 +1,162 @@
+use crate::packet::TCPPacket;
+use crate::route;
+use anyhow::{Context, Result};
+use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, Packet};
+use pnet::transport::{self, TransportChannelType, TransportProtocol, TransportSender};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Mutex;
+use std::thread;
+
+// TCPセグメントの送受信を担うバックエンド
+// TCPはこのトレイトを介してのみパケットを送受信するため、
+// 実装を差し替えることでカーネルのRAWソケット以外(シミュレータ、TUNデバイス、テストダブルなど)の上でも動作する
+pub trait Device: Send + Sync + 'static {
+    // セグメントをdstへ送信し、送信したバイト数を返す
+    fn send(&self, packet: &TCPPacket, src: IpAddr, dst: IpAddr) -> Result<usize>;
+
+    // セグメントを受信するまでブロックし、(セグメント, 送信元アドレス, 宛先アドレス)を返す
+    fn recv(&self) -> Result<(TCPPacket, IpAddr, IpAddr)>;
+
+    // 宛先addrへ送信する際に送信元として利用するローカルのIPアドレスを返す
+    fn source_addr_to(&self, addr: IpAddr) -> Result<IpAddr>;
+
+    // どのソケットにも該当しないセグメントに対してRSTを返すかどうか
+    // アドレスをカーネルと共有するバックエンドでは、カーネル自身の接続を切断しないようにfalseを返す
+    fn resets_unmatched(&self) -> bool {
+        true
+    }
+}
+
+// pnetのトランスポートチャネル(カーネルのRAWソケット)を利用するデフォルトのバックエンド
+// IPv4とIPv6のそれぞれにチャネルを開く
+pub struct PnetDevice {
+    sender_v4: Mutex<TransportSender>,
+    // IPv6が無効なホストではRAWソケットを開けないので、IPv4だけで動作する
+    sender_v6: Option<Mutex<TransportSender>>,
+    // IPv4とIPv6の受信スレッドが受信したセグメントをまとめて受け取る
+    receiver: Mutex<Receiver<(TCPPacket, IpAddr, IpAddr)>>,
+}
+
+impl PnetDevice {
+    pub fn new() -> Result<Self> {
+        let (sender_v4, _) = transport::transport_channel(
+            65535,
+            TransportChannelType::Layer4(TransportProtocol::Ipv4(IpNextHeaderProtocols::Tcp)),
+        )?;
+        let (_, mut receiver_v4) = transport::transport_channel(
+            65535,
+            // IPアドレスが必要なのでIPパケットレベルで取得
+            TransportChannelType::Layer3(IpNextHeaderProtocols::Tcp),
+        )?;
+        // IPv6のRAWソケットはIPヘッダを含めて送受信できないので、どちらもトランスポート層で扱う
+        let channel_v6 = transport::transport_channel(
+            65535,
+            TransportChannelType::Layer4(TransportProtocol::Ipv6(IpNextHeaderProtocols::Tcp)),
+        );
+
+        let (sender, receiver) = mpsc::channel();
+        let cloned_sender = sender.clone();
+        thread::spawn(move || {
+            let mut packet_iter = transport::ipv4_packet_iter(&mut receiver_v4);
+            loop {
+                // パケットを受信するまでスレッドをブロックして待機する
+                let (packet, remote_addr) = match packet_iter.next() {
+                    Ok((p, r)) => (p, r),
+                    Err(_) => continue,
+                };
+                let local_addr = IpAddr::V4(packet.get_destination());
+                // pnetのTcpPacketを生成
+                let tcp_packet = match TcpPacket::new(packet.payload()) {
+                    Some(p) => p,
+                    None => continue,
+                };
+                // pnetのTcpPacketからtcp::TCPPacketに変換する
+                let segment = (TCPPacket::from(tcp_packet), remote_addr, local_addr);
+                if cloned_sender.send(segment).is_err() {
+                    return;
+                }
+            }
+        });
+        let (sender_v6, mut receiver_v6) = match channel_v6 {
+            Ok(channel) => channel,
+            Err(error) => {
+                // IPv6の宛先に送信するまではエラーにしない
+                dbg!("failed to open ipv6 raw socket", error);
+                return Ok(Self {
+                    sender_v4: Mutex::new(sender_v4),
+                    sender_v6: None,
+                    receiver: Mutex::new(receiver),
+                });
+            }
+        };
+        thread::spawn(move || {
+            // IPv6ではIPヘッダを受け取れないため宛先アドレスが分からない。
+            // 送信元への経路の送信元アドレスを宛先とみなす。経路の検索結果は送信元ごとに覚えておく
+            let mut local_addrs = HashMap::new();
+            let mut packet_iter = transport::tcp_packet_iter(&mut receiver_v6);
+            loop {
+                let (tcp_packet, remote_addr) = match packet_iter.next() {
+                    Ok((p, r)) => (p, r),
+                    Err(_) => continue,
+                };
+                let local_addr = match local_addrs.get(&remote_addr) {
+                    Some(addr) => *addr,
+                    None => match route::lookup(remote_addr) {
+                        Ok(route) => *local_addrs.entry(remote_addr).or_insert(route.source),
+                        Err(_) => continue,
+                    },
+                };
+                let segment = (TCPPacket::from(tcp_packet), remote_addr, local_addr);
+                if sender.send(segment).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Self {
+            sender_v4: Mutex::new(sender_v4),
+            sender_v6: Some(Mutex::new(sender_v6)),
+            receiver: Mutex::new(receiver),
+        })
+    }
+}
+
+impl Device for PnetDevice {
+    fn send(&self, packet: &TCPPacket, _src: IpAddr, dst: IpAddr) -> Result<usize> {
+        // 送信元アドレスはカーネルがIPヘッダを組み立てる際に決定する
+        let sender = match dst {
+            IpAddr::V4(_) => &self.sender_v4,
+            IpAddr::V6(_) => self
+                .sender_v6
+                .as_ref()
+                .context("ipv6 raw socket is not available")?,
+        };
+        sender
+            .lock()
+            .unwrap()
+            .send_to(packet.clone(), dst)
+            .context(format!("failed to send: \n{:?}", packet))
+    }
+
+    fn recv(&self) -> Result<(TCPPacket, IpAddr, IpAddr)> {
+        self.receiver
+            .lock()
+            .unwrap()
+            .recv()
+            .context("receive thread terminated")
+    }
+
+    fn source_addr_to(&self, addr: IpAddr) -> Result<IpAddr> {
+        // 送信元アドレスの選択はカーネルのルーティングテーブルに従う
+        let route = route::lookup(addr)?;
+        dbg!("route", &route);
+        Ok(route.source)
+    }
+
+    fn resets_unmatched(&self) -> bool {
+        // 受信チャネルにはカーネル自身の接続のセグメントも届く。該当するソケットがなければカーネルに任せる
+        false
+    }
+}
@@ -0,0 +1,218 @@
+use crate::device::Device;
+use crate::ip;
+use crate::packet::TCPPacket;
+use anyhow::{Context, Result};
+use pnet::datalink::{self, Channel, DataLinkReceiver, DataLinkSender};
+use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket};
+use pnet::packet::ethernet::{EtherType, EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Mutex;
+
+const ETHERNET_HEADER_SIZE: usize = 14;
+const ARP_PACKET_SIZE: usize = 28;
+// アドレス解決を待つ間に宛先ごとに保持しておくパケット数の上限
+const MAX_PENDING_PACKETS: usize = 16;
+
+// AF_PACKETソケットでEthernetフレームを直接送受信するバックエンド
+// ネクストホップのMACアドレスは自前のARPキャッシュで解決し、IPv4ヘッダも自前で組み立てる。
+// L2からL4までをtoytcpで扱うことができる。
+//
+// インタフェースのMACアドレスはカーネルと共有するが、IPアドレスはカーネルが所有していないものを使う。
+// カーネルは自分宛てでないセグメントにRSTを返さず、そのアドレスに対するARPにも応答しない。
+pub struct EthernetDevice {
+    mac: MacAddr,
+    addr: Ipv4Addr,
+    prefix_len: u8,
+    gateway: Option<Ipv4Addr>,
+    sender: Mutex<Box<dyn DataLinkSender>>,
+    receiver: Mutex<Box<dyn DataLinkReceiver>>,
+    arp_cache: Mutex<HashMap<Ipv4Addr, MacAddr>>,
+    // アドレス解決を待っているIPパケット
+    pending: Mutex<HashMap<Ipv4Addr, Vec<Vec<u8>>>>,
+}
+
+impl EthernetDevice {
+    // インタフェースifnameに接続し、addr/prefix_lenを自身のアドレスとして応答する
+    // サブネット外の宛先へはgatewayを経由して送信する
+    pub fn new(
+        ifname: &str,
+        addr: Ipv4Addr,
+        prefix_len: u8,
+        gateway: Option<Ipv4Addr>,
+    ) -> Result<Self> {
+        let interface = datalink::interfaces()
+            .into_iter()
+            .find(|i| i.name == ifname)
+            .context(format!("no such interface: {}", ifname))?;
+        let mac = interface
+            .mac
+            .context(format!("interface has no MAC address: {}", ifname))?;
+        let (sender, receiver) = match datalink::channel(&interface, Default::default())? {
+            Channel::Ethernet(sender, receiver) => (sender, receiver),
+            _ => anyhow::bail!("unsupported channel type: {}", ifname),
+        };
+        Ok(Self {
+            mac,
+            addr,
+            prefix_len,
+            gateway,
+            sender: Mutex::new(sender),
+            receiver: Mutex::new(receiver),
+            arp_cache: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // 宛先に対するネクストホップのアドレスを決める
+    fn next_hop(&self, dst: Ipv4Addr) -> Result<Ipv4Addr> {
+        let mask = u32::MAX
+            .checked_shl(32 - self.prefix_len as u32)
+            .unwrap_or(0);
+        if u32::from(dst) & mask == u32::from(self.addr) & mask {
+            Ok(dst)
+        } else {
+            self.gateway.context(format!("no route to {}", dst))
+        }
+    }
+
+    fn send_frame(&self, dst: MacAddr, ethertype: EtherType, payload: &[u8]) -> Result<()> {
+        let mut buffer = vec![0; ETHERNET_HEADER_SIZE + payload.len()];
+        let mut frame = MutableEthernetPacket::new(&mut buffer).unwrap();
+        frame.set_destination(dst);
+        frame.set_source(self.mac);
+        frame.set_ethertype(ethertype);
+        frame.set_payload(payload);
+        self.sender
+            .lock()
+            .unwrap()
+            .send_to(&buffer, None)
+            .context("failed to send frame")??;
+        Ok(())
+    }
+
+    fn send_arp(
+        &self,
+        operation: pnet::packet::arp::ArpOperation,
+        dst_mac: MacAddr,
+        target_mac: MacAddr,
+        target_addr: Ipv4Addr,
+    ) -> Result<()> {
+        let mut buffer = [0; ARP_PACKET_SIZE];
+        let mut arp = MutableArpPacket::new(&mut buffer).unwrap();
+        arp.set_hardware_type(ArpHardwareTypes::Ethernet);
+        arp.set_protocol_type(EtherTypes::Ipv4);
+        arp.set_hw_addr_len(6);
+        arp.set_proto_addr_len(4);
+        arp.set_operation(operation);
+        arp.set_sender_hw_addr(self.mac);
+        arp.set_sender_proto_addr(self.addr);
+        arp.set_target_hw_addr(target_mac);
+        arp.set_target_proto_addr(target_addr);
+        self.send_frame(dst_mac, EtherTypes::Arp, &buffer)
+    }
+
+    // 受信したARPパケットを処理する
+    // https://datatracker.ietf.org/doc/html/rfc826
+    fn handle_arp(&self, arp: &ArpPacket) -> Result<()> {
+        let sender_addr = arp.get_sender_proto_addr();
+        let sender_mac = arp.get_sender_hw_addr();
+        let is_target = arp.get_target_proto_addr() == self.addr;
+        {
+            let mut cache = self.arp_cache.lock().unwrap();
+            // 既にキャッシュにあるか、自分宛てのARPであれば送信元の対応を記録する
+            if is_target || cache.contains_key(&sender_addr) {
+                cache.insert(sender_addr, sender_mac);
+            }
+        }
+        if !is_target {
+            return Ok(());
+        }
+        // アドレス解決を待っていたパケットを送信する
+        let pending = self.pending.lock().unwrap().remove(&sender_addr);
+        for packet in pending.into_iter().flatten() {
+            self.send_frame(sender_mac, EtherTypes::Ipv4, &packet)?;
+        }
+        if arp.get_operation() == ArpOperations::Request {
+            dbg!("arp reply", sender_addr);
+            self.send_arp(ArpOperations::Reply, sender_mac, sender_mac, sender_addr)?;
+        }
+        Ok(())
+    }
+}
+
+impl Device for EthernetDevice {
+    fn send(&self, packet: &TCPPacket, src: IpAddr, dst: IpAddr) -> Result<usize> {
+        // 近隣探索は実装していないのでIPv6には対応しない
+        let (src, dst) = match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => (src, dst),
+            _ => anyhow::bail!("IPv6 is not supported: {} -> {}", src, dst),
+        };
+        let next_hop = self.next_hop(dst)?;
+        let ip_packet = ip::build_ipv4_packet(packet, src, dst);
+        let mac = self.arp_cache.lock().unwrap().get(&next_hop).copied();
+        match mac {
+            Some(mac) => self.send_frame(mac, EtherTypes::Ipv4, &ip_packet)?,
+            None => {
+                // MACアドレスが未解決であればパケットを保留してARPリクエストを送信する
+                // 解決できなかった場合に失われたパケットはTCPの再送に任せる
+                let mut pending = self.pending.lock().unwrap();
+                let queue = pending.entry(next_hop).or_default();
+                if queue.len() < MAX_PENDING_PACKETS {
+                    queue.push(ip_packet.clone());
+                }
+                drop(pending);
+                dbg!("arp request", next_hop);
+                self.send_arp(
+                    ArpOperations::Request,
+                    MacAddr::broadcast(),
+                    MacAddr::zero(),
+                    next_hop,
+                )?;
+            }
+        }
+        Ok(ip_packet.len())
+    }
+
+    fn recv(&self) -> Result<(TCPPacket, IpAddr, IpAddr)> {
+        let mut receiver = self.receiver.lock().unwrap();
+        loop {
+            let frame = match receiver.next() {
+                Ok(frame) => frame,
+                Err(_) => continue,
+            };
+            let frame = match EthernetPacket::new(frame) {
+                Some(f) => f,
+                None => continue,
+            };
+            // 自身(とインタフェースを共有するカーネル)が送信したフレームは無視
+            if frame.get_source() == self.mac {
+                continue;
+            }
+            match frame.get_ethertype() {
+                EtherTypes::Arp => {
+                    if let Some(arp) = ArpPacket::new(frame.payload()) {
+                        if let Err(error) = self.handle_arp(&arp) {
+                            dbg!(error);
+                        }
+                    }
+                }
+                EtherTypes::Ipv4 => {
+                    if let Some((packet, src, dst)) = ip::parse_ipv4_packet(frame.payload()) {
+                        // 自身のアドレス宛て以外は無視
+                        if dst == self.addr {
+                            return Ok((packet, src.into(), dst.into()));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn source_addr_to(&self, _addr: IpAddr) -> Result<IpAddr> {
+        Ok(self.addr.into())
+    }
+}
@@ -0,0 +1,337 @@
+use crate::clock::Clock;
+use crate::device::Device;
+use crate::packet::TCPPacket;
+use anyhow::{Context, Result};
+use pnet::packet::{tcp::TcpPacket, Packet};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use std::net::IpAddr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// 並べ替えのために保留しているセグメントを、ウィンドウが埋まらなくても送り出すまでの時間
+const REORDER_TIMEOUT: Duration = Duration::from_millis(200);
+// 遅延中のセグメントの配送時刻を確認する間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// (セグメント, 送信元アドレス, 宛先アドレス)
+type Segment = (TCPPacket, IpAddr, IpAddr);
+
+// 一方向のリンクに加える障害の設定
+// 既定値では何も起こさない
+#[derive(Clone, Debug, Default)]
+pub struct FaultConfig {
+    pub loss: f64,             // セグメントを破棄する確率
+    pub duplicate: f64,        // セグメントを複製する確率
+    pub reorder_window: usize, // この個数ずつまとめたセグメントを順不同で送り出す。0なら並べ替えない
+    pub corrupt: f64,          // セグメントの1ビットを反転させる確率
+    pub delay: Duration,       // 固定の遅延
+    pub jitter: Duration,      // 遅延に加える0以上jitter以下のゆらぎ
+    pub seed: u64,             // 乱数のシード。同じシードなら同じ障害が再現する
+}
+
+// 送受信されるセグメントに障害を注入するバックエンド
+// 内側のデバイスをラップし、送信方向と受信方向にそれぞれ別の設定を適用する。
+// 再送やチェックサム、並べ替えの処理を意図的に発生させるために利用する。
+pub struct FaultInjector<D: Device> {
+    inner: Arc<D>,
+    outbound: Arc<Link>,
+    receiver: Mutex<Receiver<Segment>>,
+}
+
+impl<D: Device> FaultInjector<D> {
+    pub fn new(
+        inner: D,
+        outbound: FaultConfig,
+        inbound: FaultConfig,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let inner = Arc::new(inner);
+
+        // 送信方向: 障害を加えた後、内側のデバイスから送信する
+        let outbound = Arc::new(Link::new(outbound, clock.clone()));
+        let cloned_inner = inner.clone();
+        let cloned_link = outbound.clone();
+        thread::spawn(move || {
+            cloned_link.run(|(packet, src, dst)| {
+                if let Err(error) = cloned_inner.send(&packet, src, dst) {
+                    dbg!(error);
+                }
+            })
+        });
+
+        // 受信方向: 内側のデバイスで受信したセグメントに障害を加えた後、recvに渡す
+        let inbound = Arc::new(Link::new(inbound, clock));
+        let (sender, receiver): (Sender<Segment>, _) = mpsc::channel();
+        let cloned_link = inbound.clone();
+        thread::spawn(move || {
+            cloned_link.run(|segment| {
+                let _ = sender.send(segment);
+            })
+        });
+        let cloned_inner = inner.clone();
+        thread::spawn(move || loop {
+            match cloned_inner.recv() {
+                Ok(segment) => inbound.push(segment),
+                Err(error) => {
+                    dbg!(error);
+                    return;
+                }
+            }
+        });
+
+        Self {
+            inner,
+            outbound,
+            receiver: Mutex::new(receiver),
+        }
+    }
+}
+
+impl<D: Device> Device for FaultInjector<D> {
+    fn send(&self, packet: &TCPPacket, src: IpAddr, dst: IpAddr) -> Result<usize> {
+        // 破棄された場合も送信には成功したように見せる
+        self.outbound.push((packet.clone(), src, dst));
+        Ok(packet.packet().len())
+    }
+
+    fn recv(&self) -> Result<(TCPPacket, IpAddr, IpAddr)> {
+        self.receiver
+            .lock()
+            .unwrap()
+            .recv()
+            .context("fault injector is disconnected")
+    }
+
+    fn source_addr_to(&self, addr: IpAddr) -> Result<IpAddr> {
+        self.inner.source_addr_to(addr)
+    }
+
+    fn resets_unmatched(&self) -> bool {
+        self.inner.resets_unmatched()
+    }
+}
+
+// 障害を加える一方向のリンク
+struct Link {
+    state: Mutex<LinkState>,
+    condvar: Condvar,
+    clock: Arc<dyn Clock>,
+}
+
+struct LinkState {
+    config: FaultConfig,
+    rng: StdRng,
+    // 並べ替えのために保留しているセグメントと、保留を始めた時刻
+    reorder_buffer: Vec<Segment>,
+    reorder_started: SystemTime,
+    // 配送を待っているセグメント。(配送時刻, 到着順, セグメント)
+    scheduled: Vec<(SystemTime, u64, Segment)>,
+    counter: u64,
+}
+
+impl Link {
+    fn new(config: FaultConfig, clock: Arc<dyn Clock>) -> Self {
+        let rng = StdRng::seed_from_u64(config.seed);
+        Self {
+            state: Mutex::new(LinkState {
+                config,
+                rng,
+                reorder_buffer: Vec::new(),
+                reorder_started: SystemTime::UNIX_EPOCH,
+                scheduled: Vec::new(),
+                counter: 0,
+            }),
+            condvar: Condvar::new(),
+            clock,
+        }
+    }
+
+    // リンクにセグメントを流し込む
+    fn push(&self, (packet, src, dst): Segment) {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        if state.rng.gen::<f64>() < state.config.loss {
+            dbg!("fault: loss", &packet);
+            return;
+        }
+        let packet = if state.rng.gen::<f64>() < state.config.corrupt {
+            dbg!("fault: corrupt", &packet);
+            corrupt(&packet, &mut state.rng)
+        } else {
+            packet
+        };
+        let copies = if state.rng.gen::<f64>() < state.config.duplicate {
+            dbg!("fault: duplicate", &packet);
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let segment = (packet.clone(), src, dst);
+            if state.config.reorder_window > 0 {
+                if state.reorder_buffer.is_empty() {
+                    state.reorder_started = now;
+                }
+                state.reorder_buffer.push(segment);
+                if state.reorder_buffer.len() >= state.config.reorder_window {
+                    state.flush_reorder_buffer(now);
+                }
+            } else {
+                state.schedule(now, segment);
+            }
+        }
+        self.condvar.notify_all();
+    }
+
+    // 配送時刻になったセグメントを順にdeliverへ渡し続ける
+    fn run(&self, mut deliver: impl FnMut(Segment)) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let now = self.clock.now();
+            if !state.reorder_buffer.is_empty() && now >= state.reorder_started + REORDER_TIMEOUT
+            {
+                state.flush_reorder_buffer(now);
+            }
+            // 配送時刻が最も早く、同時刻なら先に到着したセグメントを取り出す
+            let next = state
+                .scheduled
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, (time, order, _))| (*time, *order))
+                .map(|(i, (time, _, _))| (i, *time));
+            match next {
+                Some((i, time)) if time <= now => {
+                    let (_, _, segment) = state.scheduled.swap_remove(i);
+                    // 配送中はロックを外し、送信側をブロックしないようにする
+                    drop(state);
+                    deliver(segment);
+                    state = self.state.lock().unwrap();
+                }
+                None if state.reorder_buffer.is_empty() => {
+                    // 配送待ちのセグメントがなければ次のセグメントが流し込まれるまで待機
+                    state = self.condvar.wait(state).unwrap();
+                }
+                _ => {
+                    drop(state);
+                    self.clock.sleep(POLL_INTERVAL);
+                    state = self.state.lock().unwrap();
+                }
+            }
+        }
+    }
+}
+
+impl LinkState {
+    // 保留していたセグメントを順不同で配送待ちにする
+    fn flush_reorder_buffer(&mut self, now: SystemTime) {
+        let mut buffer = std::mem::take(&mut self.reorder_buffer);
+        buffer.shuffle(&mut self.rng);
+        for segment in buffer {
+            self.schedule(now, segment);
+        }
+    }
+
+    // 遅延を加えた配送時刻を決めて配送待ちにする
+    fn schedule(&mut self, now: SystemTime, segment: Segment) {
+        let jitter = if self.config.jitter > Duration::ZERO {
+            self.rng.gen_range(Duration::ZERO..=self.config.jitter)
+        } else {
+            Duration::ZERO
+        };
+        let time = now + self.config.delay + jitter;
+        self.scheduled.push((time, self.counter, segment));
+        self.counter += 1;
+    }
+}
+
+// セグメントのランダムな1ビットを反転させる
+fn corrupt(packet: &TCPPacket, rng: &mut StdRng) -> TCPPacket {
+    let mut buffer = packet.packet().to_vec();
+    let bit = rng.gen_range(0..buffer.len() * 8);
+    buffer[bit / 8] ^= 1 << (bit % 8);
+    TCPPacket::from(TcpPacket::new(&buffer).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::packet::TCPPacketBuilder;
+    use crate::seq::SeqNum;
+    use std::net::Ipv4Addr;
+
+    const SRC: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const DST: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1));
+
+    fn segment(seq: u32) -> TCPPacket {
+        TCPPacketBuilder::new()
+            .src(40000)
+            .dest(50000)
+            .seq(SeqNum::new(seq))
+            .payload(b"hello")
+            .build(SRC, DST)
+            .unwrap()
+    }
+
+    // 0から99のシーケンス番号のセグメントを流し込み、配送される順に並べたシーケンス番号を返す
+    // 時計は進めないので、遅延がなければ配送待ちに加えられた順に配送される
+    // 並べ替えのために保留中のセグメントは、その後に保留した順に並べる
+    fn pattern(config: FaultConfig) -> Vec<u32> {
+        let link = Link::new(config, Arc::new(VirtualClock::new()));
+        for seq in 0..100 {
+            link.push((segment(seq), SRC, DST));
+        }
+        let state = link.state.lock().unwrap();
+        let scheduled = state.scheduled.iter().map(|(_, _, segment)| segment);
+        scheduled
+            .chain(state.reorder_buffer.iter())
+            .map(|(packet, _, _)| packet.get_seq().value())
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_reproduces_faults() {
+        let config = |seed| FaultConfig {
+            loss: 0.2,
+            duplicate: 0.2,
+            reorder_window: 4,
+            seed,
+            ..Default::default()
+        };
+        let faults = pattern(config(1));
+        assert_eq!(pattern(config(1)), faults);
+        assert_ne!(pattern(config(2)), faults);
+
+        // 破棄、複製、並べ替えがどれも起きている
+        let mut delivered = faults.clone();
+        delivered.sort();
+        delivered.dedup();
+        assert!(delivered.len() < 100);
+        assert!(delivered.len() < faults.len());
+        assert!(faults.windows(2).any(|pair| pair[0] > pair[1]));
+    }
+
+    // 障害を設定しなければ、流し込んだ順にそのまま配送される
+    #[test]
+    fn default_config_has_no_faults() {
+        assert_eq!(
+            pattern(FaultConfig::default()),
+            (0..100).collect::<Vec<_>>()
+        );
+    }
+
+    // 1ビットの反転は必ずチェックサムで検出できる
+    #[test]
+    fn corrupted_segment_fails_checksum() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let packet = segment(0);
+        assert!(packet.is_correct_checksum(SRC, DST));
+        for _ in 0..100 {
+            let corrupted = corrupt(&packet, &mut rng);
+            assert_ne!(corrupted.packet(), packet.packet());
+            assert!(!corrupted.is_correct_checksum(SRC, DST));
+        }
+    }
+}
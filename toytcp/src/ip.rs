@@ -0,0 +1,96 @@
+use crate::packet::TCPPacket;
+use anyhow::Result;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet::packet::tcp::TcpPacket;
+use pnet::packet::Packet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const DEFAULT_TTL: u8 = 64;
+
+// カーネルを介さずに送信するバックエンドのために、TCPセグメントをIPパケットにカプセル化する
+pub fn build_ip_packet(packet: &TCPPacket, src: IpAddr, dst: IpAddr) -> Result<Vec<u8>> {
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => Ok(build_ipv4_packet(packet, src, dst)),
+        (IpAddr::V6(src), IpAddr::V6(dst)) => Ok(build_ipv6_packet(packet, src, dst)),
+        _ => anyhow::bail!("address family mismatch: {} -> {}", src, dst),
+    }
+}
+
+// IPパケットからTCPセグメントを取り出し、(セグメント, 送信元アドレス, 宛先アドレス)を返す
+// バージョンフィールドを見てIPv4とIPv6を判別する
+pub fn parse_ip_packet(buffer: &[u8]) -> Option<(TCPPacket, IpAddr, IpAddr)> {
+    match buffer.first()? >> 4 {
+        4 => parse_ipv4_packet(buffer).map(|(p, src, dst)| (p, src.into(), dst.into())),
+        6 => parse_ipv6_packet(buffer).map(|(p, src, dst)| (p, src.into(), dst.into())),
+        _ => None,
+    }
+}
+
+pub fn build_ipv4_packet(packet: &TCPPacket, src: Ipv4Addr, dst: Ipv4Addr) -> Vec<u8> {
+    let segment = packet.packet();
+    let mut buffer = vec![0; IPV4_HEADER_SIZE + segment.len()];
+    let mut ip_packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+    ip_packet.set_version(4);
+    ip_packet.set_header_length((IPV4_HEADER_SIZE / 4) as u8);
+    ip_packet.set_total_length((IPV4_HEADER_SIZE + segment.len()) as u16);
+    // フラグメント化は行わない
+    ip_packet.set_flags(Ipv4Flags::DontFragment);
+    ip_packet.set_ttl(DEFAULT_TTL);
+    ip_packet.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+    ip_packet.set_source(src);
+    ip_packet.set_destination(dst);
+    ip_packet.set_payload(segment);
+    let checksum = ipv4::checksum(&ip_packet.to_immutable());
+    ip_packet.set_checksum(checksum);
+    buffer
+}
+
+// TCP以外のパケットやフラグメント化されたパケットはNoneになる
+pub fn parse_ipv4_packet(buffer: &[u8]) -> Option<(TCPPacket, Ipv4Addr, Ipv4Addr)> {
+    let ip_packet = Ipv4Packet::new(buffer)?;
+    if ip_packet.get_version() != 4
+        || ip_packet.get_next_level_protocol() != IpNextHeaderProtocols::Tcp
+        || ip_packet.get_flags() & Ipv4Flags::MoreFragments > 0
+        || ip_packet.get_fragment_offset() > 0
+    {
+        return None;
+    }
+    let tcp_packet = TcpPacket::new(ip_packet.payload())?;
+    Some((
+        TCPPacket::from(tcp_packet),
+        ip_packet.get_source(),
+        ip_packet.get_destination(),
+    ))
+}
+
+pub fn build_ipv6_packet(packet: &TCPPacket, src: Ipv6Addr, dst: Ipv6Addr) -> Vec<u8> {
+    let segment = packet.packet();
+    let mut buffer = vec![0; IPV6_HEADER_SIZE + segment.len()];
+    let mut ip_packet = MutableIpv6Packet::new(&mut buffer).unwrap();
+    ip_packet.set_version(6);
+    ip_packet.set_payload_length(segment.len() as u16);
+    ip_packet.set_next_header(IpNextHeaderProtocols::Tcp);
+    ip_packet.set_hop_limit(DEFAULT_TTL);
+    ip_packet.set_source(src);
+    ip_packet.set_destination(dst);
+    ip_packet.set_payload(segment);
+    buffer
+}
+
+// 拡張ヘッダを持つパケットはNoneになる
+pub fn parse_ipv6_packet(buffer: &[u8]) -> Option<(TCPPacket, Ipv6Addr, Ipv6Addr)> {
+    let ip_packet = Ipv6Packet::new(buffer)?;
+    if ip_packet.get_version() != 6 || ip_packet.get_next_header() != IpNextHeaderProtocols::Tcp {
+        return None;
+    }
+    let tcp_packet = TcpPacket::new(ip_packet.payload())?;
+    Some((
+        TCPPacket::from(tcp_packet),
+        ip_packet.get_source(),
+        ip_packet.get_destination(),
+    ))
+}
@@ -1,4 +1,23 @@
-mod packet;
+pub mod bbr;
+pub mod clock;
+pub mod congestion;
+pub mod cubic;
+pub mod device;
+pub mod ethernet;
+pub mod fault;
+mod ip;
+pub mod packet;
+pub mod pcap;
+pub mod rate;
+mod reassembly;
+pub mod replay;
+pub mod route;
+mod rtt;
+pub mod seq;
+pub mod sim;
 mod socket;
+pub mod stats;
 pub mod tcp;
 mod tcpflags;
+pub mod tcpoption;
+pub mod tun;
@@ -1,9 +1,13 @@
+use crate::seq::SeqNum;
 use crate::tcpflags;
+use crate::tcpoption::{self, TcpOption, MAX_OPTIONS_SIZE};
+use anyhow::Result;
 use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, Packet};
 use pnet::util;
 
 use std::fmt::{self, Debug};
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
+// オプションを含まない固定長部分の大きさ
 const TCP_HEADER_SIZE: usize = 20;
 
 // TCPヘッダーフォーマット
@@ -35,10 +39,13 @@ pub struct TCPPacket {
 }
 
 impl TCPPacket {
+    // オプションを含まないセグメントを作る。オプションを付ける場合はTCPPacketBuilderを使う
     pub fn new(payload_len: usize) -> Self {
-        Self {
+        let mut packet = Self {
             buffer: vec![0; TCP_HEADER_SIZE + payload_len],
-        }
+        };
+        packet.set_data_offset((TCP_HEADER_SIZE / 4) as u8);
+        packet
     }
 
     pub fn get_src(&self) -> u16 {
@@ -57,22 +64,27 @@ impl TCPPacket {
         u16::from_be_bytes([self.buffer[2], self.buffer[3]])
     }
 
-    pub fn get_seq(&self) -> u32 {
-        u32::from_be_bytes([
+    pub fn get_seq(&self) -> SeqNum {
+        SeqNum::new(u32::from_be_bytes([
             self.buffer[4],
             self.buffer[5],
             self.buffer[6],
             self.buffer[7],
-        ])
+        ]))
     }
 
-    pub fn get_ack(&self) -> u32 {
-        u32::from_be_bytes([
+    pub fn get_ack(&self) -> SeqNum {
+        SeqNum::new(u32::from_be_bytes([
             self.buffer[8],
             self.buffer[9],
             self.buffer[10],
             self.buffer[11],
-        ])
+        ]))
+    }
+
+    // ヘッダ長(4バイト単位)
+    pub fn get_data_offset(&self) -> u8 {
+        self.buffer[12] >> 4
     }
 
     pub fn get_flag(&self) -> u8 {
@@ -95,16 +107,16 @@ impl TCPPacket {
         self.buffer[2..4].copy_from_slice(&port.to_be_bytes())
     }
 
-    pub fn set_seq(&mut self, num: u32) {
-        self.buffer[4..8].copy_from_slice(&num.to_be_bytes())
+    pub fn set_seq(&mut self, num: SeqNum) {
+        self.buffer[4..8].copy_from_slice(&num.value().to_be_bytes())
     }
 
-    pub fn set_ack(&mut self, num: u32) {
-        self.buffer[8..12].copy_from_slice(&num.to_be_bytes())
+    pub fn set_ack(&mut self, num: SeqNum) {
+        self.buffer[8..12].copy_from_slice(&num.value().to_be_bytes())
     }
 
     pub fn set_data_offset(&mut self, offset: u8) {
-        self.buffer[12] |= offset << 4;
+        self.buffer[12] = (self.buffer[12] & 0x0f) | (offset << 4);
     }
 
     pub fn set_flag(&mut self, flag: u8) {
@@ -120,20 +132,52 @@ impl TCPPacket {
     }
 
     pub fn set_payload(&mut self, payload: &[u8]) {
-        self.buffer[TCP_HEADER_SIZE..TCP_HEADER_SIZE + payload.len() as usize]
-            .copy_from_slice(payload)
+        let header_len = self.header_len();
+        self.buffer[header_len..header_len + payload.len()].copy_from_slice(payload)
+    }
+
+    // データオフセットが示すヘッダ長(バイト)。不正な値であっても、バッファの範囲に収まるよう丸める
+    pub fn header_len(&self) -> usize {
+        (self.get_data_offset() as usize * 4).clamp(TCP_HEADER_SIZE, self.buffer.len())
     }
 
-    pub fn is_correct_checksum(&self, local_addr: Ipv4Addr, remote_addr: Ipv4Addr) -> bool {
-        self.get_checksum()
-            == util::ipv4_checksum(
-                &self.packet(),
+    // データオフセットが固定長部分より小さいか、セグメントの長さを超えるものは不正
+    pub fn is_valid_data_offset(&self) -> bool {
+        let header_len = self.get_data_offset() as usize * 4;
+        (TCP_HEADER_SIZE..=self.buffer.len()).contains(&header_len)
+    }
+
+    pub fn options(&self) -> Vec<TcpOption> {
+        tcpoption::parse(&self.buffer[TCP_HEADER_SIZE..self.header_len()])
+    }
+
+    pub fn is_correct_checksum(&self, local_addr: IpAddr, remote_addr: IpAddr) -> bool {
+        self.get_checksum() == self.compute_checksum(local_addr, remote_addr)
+    }
+
+    // 擬似ヘッダを含めたチェックサムを計算する。チェックサムフィールド自体は計算から除外する
+    // IPv4とIPv6で擬似ヘッダの形式が異なる
+    pub fn compute_checksum(&self, src: IpAddr, dst: IpAddr) -> u16 {
+        match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => util::ipv4_checksum(
+                self.packet(),
                 8,
                 &[],
-                &local_addr,
-                &remote_addr,
+                &src,
+                &dst,
                 IpNextHeaderProtocols::Tcp,
-            )
+            ),
+            (IpAddr::V6(src), IpAddr::V6(dst)) => util::ipv6_checksum(
+                self.packet(),
+                8,
+                &[],
+                &src,
+                &dst,
+                IpNextHeaderProtocols::Tcp,
+            ),
+            // アドレスファミリが異なる組み合わせのセグメントは存在しない
+            _ => 0,
+        }
     }
 }
 
@@ -143,7 +187,7 @@ impl Packet for TCPPacket {
     }
 
     fn payload(&self) -> &[u8] {
-        &self.buffer[TCP_HEADER_SIZE..]
+        &self.buffer[self.header_len()..]
     }
 }
 
@@ -171,3 +215,93 @@ impl<'a> From<TcpPacket<'a>> for TCPPacket {
         }
     }
 }
+
+// オプションを含むセグメントを組み立てる
+// オプションは4バイト単位になるよう埋め、その長さからデータオフセットを決める
+//
+// let packet = TCPPacketBuilder::new()
+//     .src(40000)
+//     .dest(80)
+//     .flag(tcpflags::SYN)
+//     .option(TcpOption::Mss(1460))
+//     .build(local_addr, remote_addr)?;
+#[derive(Clone, Debug, Default)]
+pub struct TCPPacketBuilder<'a> {
+    src: u16,
+    dest: u16,
+    seq: SeqNum,
+    ack: SeqNum,
+    flag: u8,
+    window_size: u16,
+    options: Vec<TcpOption>,
+    payload: &'a [u8],
+}
+
+impl<'a> TCPPacketBuilder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn src(mut self, port: u16) -> Self {
+        self.src = port;
+        self
+    }
+
+    pub fn dest(mut self, port: u16) -> Self {
+        self.dest = port;
+        self
+    }
+
+    pub fn seq(mut self, num: SeqNum) -> Self {
+        self.seq = num;
+        self
+    }
+
+    pub fn ack(mut self, num: SeqNum) -> Self {
+        self.ack = num;
+        self
+    }
+
+    pub fn flag(mut self, flag: u8) -> Self {
+        self.flag = flag;
+        self
+    }
+
+    pub fn window_size(mut self, window: u16) -> Self {
+        self.window_size = window;
+        self
+    }
+
+    pub fn option(mut self, option: TcpOption) -> Self {
+        self.options.push(option);
+        self
+    }
+
+    pub fn payload(mut self, payload: &'a [u8]) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    // チェックサムを計算してセグメントを完成させる。オプションが40バイトに収まらなければエラー
+    pub fn build(self, src_addr: IpAddr, dst_addr: IpAddr) -> Result<TCPPacket> {
+        let options = tcpoption::serialize(&self.options);
+        if options.len() > MAX_OPTIONS_SIZE {
+            anyhow::bail!("tcp options too long: {} bytes", options.len());
+        }
+        let header_len = TCP_HEADER_SIZE + options.len();
+        let mut packet = TCPPacket {
+            buffer: vec![0; header_len + self.payload.len()],
+        };
+        packet.set_src(self.src);
+        packet.set_dest(self.dest);
+        packet.set_seq(self.seq);
+        packet.set_ack(self.ack);
+        packet.set_data_offset((header_len / 4) as u8);
+        packet.set_flag(self.flag);
+        packet.set_window_size(self.window_size);
+        packet.buffer[TCP_HEADER_SIZE..header_len].copy_from_slice(&options);
+        packet.set_payload(self.payload);
+        packet.set_checksum(packet.compute_checksum(src_addr, dst_addr));
+        Ok(packet)
+    }
+}
@@ -0,0 +1,238 @@
+use crate::clock::Clock;
+use crate::device::Device;
+use crate::ip;
+use crate::packet::TCPPacket;
+use anyhow::{Context, Result};
+use std::cmp;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// pcapファイルフォーマット
+// https://wiki.wireshark.org/Development/LibpcapFileFormat
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+// タイムスタンプがナノ秒単位のpcap
+const PCAP_MAGIC_NANO: u32 = 0xa1b23c4d;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const PCAP_SNAPLEN: u32 = 65535;
+// 読み込み時に受け付けるsnaplenの上限。tcpdumpの既定値(262144)
+const PCAP_MAX_SNAPLEN: u32 = 262144;
+// リンク層ヘッダを持たず、IPヘッダから始まるパケット
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+// 読み込みのみ対応するリンク層
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+// パケットをpcap形式で書き出す
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    // グローバルヘッダを書き込む
+    pub fn new(mut writer: W) -> Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        header.extend_from_slice(&PCAP_VERSION_MAJOR.to_le_bytes());
+        header.extend_from_slice(&PCAP_VERSION_MINOR.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes()); // thiszone
+        header.extend_from_slice(&0u32.to_le_bytes()); // sigfigs
+        header.extend_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        writer.write_all(&header)?;
+        Ok(Self { writer })
+    }
+
+    // IPパケットを1つ書き込む
+    pub fn write_packet(&mut self, time: SystemTime, data: &[u8]) -> Result<()> {
+        let time = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let mut record = Vec::with_capacity(16 + data.len());
+        record.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&time.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes()); // incl_len
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes()); // orig_len
+        record.extend_from_slice(data);
+        // 途中でプロセスが終了してもそれまでのパケットは読めるように、1パケットずつ書き出す
+        self.writer.write_all(&record)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// pcapファイルからIPパケットを読み出す
+// Ethernet、Linux cooked capture(tcpdump -i any)、リンク層なしのキャプチャに対応する
+pub struct PcapReader<R: Read> {
+    reader: R,
+    big_endian: bool,
+    nano: bool,
+    linktype: u32,
+    // 1レコードに含まれうる最大のバイト数
+    snaplen: usize,
+}
+
+impl<R: Read> PcapReader<R> {
+    // グローバルヘッダを読み込む
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0; 24];
+        reader
+            .read_exact(&mut header)
+            .context("failed to read pcap header")?;
+        let magic = [header[0], header[1], header[2], header[3]];
+        let (big_endian, nano) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+            (PCAP_MAGIC, _) => (false, false),
+            (PCAP_MAGIC_NANO, _) => (false, true),
+            (_, PCAP_MAGIC) => (true, false),
+            (_, PCAP_MAGIC_NANO) => (true, true),
+            _ => anyhow::bail!("not a pcap file"),
+        };
+        let mut reader = Self {
+            reader,
+            big_endian,
+            nano,
+            linktype: 0,
+            snaplen: 0,
+        };
+        // 壊れたファイルでも巨大なバッファを確保しないよう、snaplenに上限を設ける
+        reader.snaplen = match reader.u32_from(&header[16..20]) {
+            0 => PCAP_MAX_SNAPLEN,
+            snaplen => cmp::min(snaplen, PCAP_MAX_SNAPLEN),
+        } as usize;
+        reader.linktype = reader.u32_from(&header[20..24]);
+        match reader.linktype {
+            LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 | LINKTYPE_ETHERNET
+            | LINKTYPE_LINUX_SLL => Ok(reader),
+            linktype => anyhow::bail!("unsupported link type: {}", linktype),
+        }
+    }
+
+    // 次のIPパケットを(キャプチャ時刻, IPパケット)として返す。ファイルの終端ではNoneを返す
+    // IP以外のフレームは読み飛ばす
+    pub fn next_packet(&mut self) -> Result<Option<(SystemTime, Vec<u8>)>> {
+        loop {
+            let mut header = [0; 16];
+            match self.reader.read_exact(&mut header) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+            let secs = self.u32_from(&header[0..4]) as u64;
+            let fraction = self.u32_from(&header[4..8]);
+            let incl_len = self.u32_from(&header[8..12]) as usize;
+            if incl_len > self.snaplen {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "pcap record length {} exceeds snaplen {}",
+                        incl_len, self.snaplen
+                    ),
+                )
+                .into());
+            }
+            let mut data = vec![0; incl_len];
+            self.reader
+                .read_exact(&mut data)
+                .context("truncated pcap record")?;
+            let fraction = if self.nano {
+                Duration::from_nanos(fraction as u64)
+            } else {
+                Duration::from_micros(fraction as u64)
+            };
+            let time = UNIX_EPOCH + Duration::from_secs(secs) + fraction;
+            if let Some(ip_packet) = self.strip_link_header(&data) {
+                return Ok(Some((time, ip_packet.to_vec())));
+            }
+        }
+    }
+
+    // リンク層ヘッダを取り除き、IPパケットの部分を返す
+    fn strip_link_header<'a>(&self, data: &'a [u8]) -> Option<&'a [u8]> {
+        let ethertype = |offset: usize| {
+            data.get(offset..offset + 2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        };
+        let is_ip = |ethertype| ethertype == ETHERTYPE_IPV4 || ethertype == ETHERTYPE_IPV6;
+        match self.linktype {
+            LINKTYPE_ETHERNET => match ethertype(12)? {
+                t if is_ip(t) => data.get(14..),
+                ETHERTYPE_VLAN if is_ip(ethertype(16)?) => data.get(18..),
+                _ => None,
+            },
+            LINKTYPE_LINUX_SLL if is_ip(ethertype(14)?) => data.get(16..),
+            LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => Some(data),
+            _ => None,
+        }
+    }
+
+    fn u32_from(&self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+}
+
+// 送受信する全てのセグメントをpcapファイルに記録するバックエンド
+// 内側のデバイスをラップするので、Socket::send_tcp_packetによる送信、タイマースレッドによる再送、
+// 受信スレッドでの受信がすべて記録される。IPヘッダは送信元・宛先アドレスから合成する。
+pub struct PcapCapture<D: Device> {
+    inner: D,
+    writer: Mutex<PcapWriter<File>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<D: Device> PcapCapture<D> {
+    pub fn new(inner: D, path: impl AsRef<Path>, clock: Arc<dyn Clock>) -> Result<Self> {
+        let file = File::create(&path)
+            .context(format!("failed to create {}", path.as_ref().display()))?;
+        Ok(Self {
+            inner,
+            writer: Mutex::new(PcapWriter::new(file)?),
+            clock,
+        })
+    }
+
+    fn capture(&self, packet: &TCPPacket, src: IpAddr, dst: IpAddr) {
+        let result = ip::build_ip_packet(packet, src, dst).and_then(|data| {
+            self.writer
+                .lock()
+                .unwrap()
+                .write_packet(self.clock.now(), &data)
+        });
+        if let Err(error) = result {
+            // 記録に失敗しても通信は継続する
+            dbg!("failed to capture", error);
+        }
+    }
+}
+
+impl<D: Device> Device for PcapCapture<D> {
+    fn send(&self, packet: &TCPPacket, src: IpAddr, dst: IpAddr) -> Result<usize> {
+        self.capture(packet, src, dst);
+        self.inner.send(packet, src, dst)
+    }
+
+    fn recv(&self) -> Result<(TCPPacket, IpAddr, IpAddr)> {
+        let (packet, src, dst) = self.inner.recv()?;
+        self.capture(&packet, src, dst);
+        Ok((packet, src, dst))
+    }
+
+    fn source_addr_to(&self, addr: IpAddr) -> Result<IpAddr> {
+        self.inner.source_addr_to(addr)
+    }
+
+    fn resets_unmatched(&self) -> bool {
+        self.inner.resets_unmatched()
+    }
+}
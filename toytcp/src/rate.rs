@@ -0,0 +1,124 @@
+use std::cmp;
+use std::time::{Duration, SystemTime};
+
+// 配送レートの推定
+// https://datatracker.ietf.org/doc/html/draft-cheng-iccrg-delivery-rate-estimation
+//
+// セグメントの送信時に、その時点でACKされていたバイト数と時刻を記録しておき、
+// そのセグメントがACKされた時に、送信からACKまでの間に配送されたバイト数を経過時間で割る
+//   delivery_rate = (C.delivered - P.delivered) / max(send_elapsed, ack_elapsed)
+// 送信間隔(send_elapsed)とACK間隔(ack_elapsed)の長い方を使うことで、ACKがまとめて届いても過大に見積もらない
+
+// セグメントの送信時点の配送状況。再送キューのエントリに記録する
+#[derive(Clone, Copy, Debug)]
+pub struct DeliveryState {
+    pub delivered: u64,              // 送信時点でACKされていたバイト数
+    pub delivered_time: SystemTime,  // 送信時点で最後にdeliveredが増えた時刻
+    pub first_sent_time: SystemTime, // 送信時点の測定期間の開始時刻
+    pub sent_time: SystemTime,
+    pub is_app_limited: bool, // アプリケーションが送信するデータを用意できていなかった
+}
+
+// ACKごとの配送レートの測定結果
+#[derive(Clone, Copy, Debug)]
+pub struct RateSample {
+    pub delivery_rate: Option<u64>, // バイト/秒。測定期間が短すぎて信頼できない場合はNone
+    pub delivered: u64,             // 測定期間に配送されたバイト数
+    pub prior_delivered: u64,       // 測定期間の開始時点でACKされていたバイト数
+    pub interval: Duration,
+    pub is_app_limited: bool, // 帯域ではなくアプリケーションに制限されていた期間の測定値
+}
+
+#[derive(Clone, Debug)]
+pub struct DeliveryRateEstimator {
+    delivered: u64,
+    delivered_time: SystemTime,
+    first_sent_time: SystemTime,
+    // アプリケーションに制限されている間は、この値までACKされるまでの測定をapp-limitedとする。0なら制限されていない
+    app_limited: u64,
+    min_rtt: Option<Duration>,
+    last_sample: Option<RateSample>,
+}
+
+impl DeliveryRateEstimator {
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            delivered: 0,
+            delivered_time: now,
+            first_sent_time: now,
+            app_limited: 0,
+            min_rtt: None,
+            last_sample: None,
+        }
+    }
+
+    // セグメントを送信(再送を含む)する時に呼び、エントリに記録する配送状況を返す
+    pub fn on_send(&mut self, flight_size: usize, now: SystemTime) -> DeliveryState {
+        // 送信中のデータがなければ、ここから新しい測定期間を始める
+        if flight_size == 0 {
+            self.first_sent_time = now;
+            self.delivered_time = now;
+        }
+        DeliveryState {
+            delivered: self.delivered,
+            delivered_time: self.delivered_time,
+            first_sent_time: self.first_sent_time,
+            sent_time: now,
+            is_app_limited: self.app_limited != 0,
+        }
+    }
+
+    // アプリケーションが送信するデータを使い切り、ウィンドウに余裕がある
+    pub fn on_app_limited(&mut self, flight_size: usize) {
+        self.app_limited = cmp::max(self.delivered + flight_size as u64, 1);
+    }
+
+    // ACKを受信した時に呼ぶ。newestは今回ACKされたセグメントのうち最後に送信したものの配送状況
+    // ACKされたセグメントの配送状況が分からなければNoneを返す
+    pub fn on_ack(
+        &mut self,
+        acked: usize,
+        newest: Option<DeliveryState>,
+        rtt: Option<Duration>,
+        now: SystemTime,
+    ) -> Option<RateSample> {
+        if let Some(rtt) = rtt {
+            self.min_rtt = Some(self.min_rtt.map_or(rtt, |r| cmp::min(r, rtt)));
+        }
+        self.delivered += acked as u64;
+        self.delivered_time = now;
+        if self.app_limited != 0 && self.delivered > self.app_limited {
+            self.app_limited = 0;
+        }
+
+        let newest = newest?;
+        self.first_sent_time = newest.sent_time;
+        let send_elapsed = newest
+            .sent_time
+            .duration_since(newest.first_sent_time)
+            .unwrap_or_default();
+        let ack_elapsed = now
+            .duration_since(newest.delivered_time)
+            .unwrap_or_default();
+        let interval = cmp::max(send_elapsed, ack_elapsed);
+        let delivered = self.delivered - newest.delivered;
+        // 最小RTTより短い期間では正しく測定できない
+        let valid = !interval.is_zero() && self.min_rtt.is_none_or(|min_rtt| interval >= min_rtt);
+        let sample = RateSample {
+            delivery_rate: valid.then(|| (delivered as f64 / interval.as_secs_f64()) as u64),
+            delivered,
+            prior_delivered: newest.delivered,
+            interval,
+            is_app_limited: newest.is_app_limited,
+        };
+        if valid {
+            self.last_sample = Some(sample);
+        }
+        Some(sample)
+    }
+
+    // 最後に測定できた配送レート
+    pub fn last_sample(&self) -> Option<RateSample> {
+        self.last_sample
+    }
+}
@@ -0,0 +1,124 @@
+use crate::seq::SeqNum;
+
+// 順序が入れ替わって届いたセグメントの再構築キュー
+// 受信バッファに格納済みだが、RCV.NXTから連続していないシーケンス番号の範囲を保持する
+//
+//        RCV.NXT
+// ----------|     |#####|       |###|-----
+//                 ^^^^^^^       ^^^^^ 受信済みの範囲
+//            ^^^^^       ^^^^^^^ 穴(未受信)
+//
+// 範囲は[start, end)の形で開始位置の順に並び、互いに重ならず隣接もしない。
+#[derive(Clone, Debug, Default)]
+pub struct ReassemblyQueue {
+    ranges: Vec<(SeqNum, SeqNum)>,
+}
+
+impl ReassemblyQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 受信した範囲[start, end)を追加する。重なる範囲や隣接する範囲とは1つにまとめる
+    // 範囲は全て受信ウィンドウ内にあるので、シーケンス番号の大小で比較できる
+    pub fn insert(&mut self, start: SeqNum, end: SeqNum) {
+        let (mut start, mut end) = (start, end);
+        self.ranges.retain(|&(s, e)| {
+            if s > end || e < start {
+                return true;
+            }
+            if s < start {
+                start = s;
+            }
+            if e > end {
+                end = e;
+            }
+            false
+        });
+        let index = self
+            .ranges
+            .iter()
+            .position(|&(s, _)| s > start)
+            .unwrap_or(self.ranges.len());
+        self.ranges.insert(index, (start, end));
+    }
+
+    // nextから連続して受信済みの範囲があれば取り除き、その末尾(新しいRCV.NXT)を返す
+    // 連続した範囲がなければnextをそのまま返す
+    pub fn pop_contiguous(&mut self, next: SeqNum) -> SeqNum {
+        match self.ranges.first() {
+            Some(&(start, end)) if start == next => {
+                self.ranges.remove(0);
+                end
+            }
+            _ => next,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seq(value: u32) -> SeqNum {
+        SeqNum::new(value)
+    }
+
+    #[test]
+    fn insert_keeps_ranges_sorted() {
+        let mut queue = ReassemblyQueue::new();
+        queue.insert(seq(300), seq(400));
+        queue.insert(seq(100), seq(200));
+        queue.insert(seq(500), seq(600));
+        assert_eq!(
+            queue.ranges,
+            [
+                (seq(100), seq(200)),
+                (seq(300), seq(400)),
+                (seq(500), seq(600))
+            ]
+        );
+    }
+
+    #[test]
+    fn insert_merges_overlapping_and_adjacent_ranges() {
+        let mut queue = ReassemblyQueue::new();
+        queue.insert(seq(100), seq(200));
+        queue.insert(seq(300), seq(400));
+        // 隣接する範囲
+        queue.insert(seq(200), seq(250));
+        assert_eq!(queue.ranges, [(seq(100), seq(250)), (seq(300), seq(400))]);
+        // 2つの範囲にまたがる範囲
+        queue.insert(seq(240), seq(310));
+        assert_eq!(queue.ranges, [(seq(100), seq(400))]);
+        // 既存の範囲に含まれる範囲(重複して届いたセグメント)
+        queue.insert(seq(150), seq(160));
+        assert_eq!(queue.ranges, [(seq(100), seq(400))]);
+        // 既存の範囲を含む範囲
+        queue.insert(seq(50), seq(450));
+        assert_eq!(queue.ranges, [(seq(50), seq(450))]);
+    }
+
+    #[test]
+    fn pop_contiguous_only_from_next() {
+        let mut queue = ReassemblyQueue::new();
+        queue.insert(seq(200), seq(300));
+        // 穴が埋まるまではRCV.NXTを進めない
+        assert_eq!(queue.pop_contiguous(seq(100)), seq(100));
+        queue.insert(seq(100), seq(200));
+        assert_eq!(queue.pop_contiguous(seq(100)), seq(300));
+        assert!(queue.ranges.is_empty());
+        assert_eq!(queue.pop_contiguous(seq(300)), seq(300));
+    }
+
+    #[test]
+    fn insert_across_wraparound() {
+        let mut queue = ReassemblyQueue::new();
+        let next = seq(u32::MAX - 99);
+        queue.insert(next + 200, next + 300);
+        queue.insert(next + 100, next + 200);
+        assert_eq!(queue.ranges, [(seq(0), seq(200))]);
+        queue.insert(next, next + 100);
+        assert_eq!(queue.pop_contiguous(next), seq(200));
+    }
+}
@@ -0,0 +1,210 @@
+use crate::clock::VirtualClock;
+use crate::device::Device;
+use crate::ip;
+use crate::packet::TCPPacket;
+use crate::pcap::PcapReader;
+use crate::seq::SeqNum;
+use crate::tcp::TCP;
+use crate::tcpflags;
+use anyhow::{Context, Result};
+use pnet::packet::Packet;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+// (セグメント, 送信元アドレス, 宛先アドレス)
+type Segment = (TCPPacket, IpAddr, IpAddr);
+
+// キャプチャを再生する際のtoytcp側のソケットの準備方法
+pub enum ReplaySetup {
+    // addr:portでlistenし、キャプチャ中のaddr:port宛てのセグメントを再生する
+    Listen(IpAddr, u16),
+    // addr:portへconnectし、キャプチャ中のaddr:portから送られたセグメントを再生する
+    Connect(IpAddr, u16),
+}
+
+// pcapファイルに記録されたセグメントを、ワイヤから届いたものとしてTCPの状態遷移に入力し、
+// toytcpが送信したセグメントを返す。
+//
+// toytcpが選ぶ初期シーケンス番号やポート番号はキャプチャ時と異なるため、入力するセグメントの
+// 確認応答番号と宛先ポートをtoytcp側に合わせて書き換える。
+// 返すセグメントは逆にキャプチャ側の番号に書き戻すので、キャプチャ中の実際の応答と比較できる。
+// 受信スレッドやタイマースレッドは起動せず、呼び出し元のスレッドだけで再生する。
+// そのためタイムアウトによる再送は出力に含まれず、戻った時点でTCPインスタンスは破棄されている。
+pub fn replay(path: impl AsRef<Path>, setup: ReplaySetup) -> Result<Vec<Segment>> {
+    let file =
+        File::open(&path).context(format!("failed to open {}", path.as_ref().display()))?;
+    let mut reader = PcapReader::new(BufReader::new(file))?;
+    let mut segments = Vec::new();
+    while let Some((_, data)) = reader.next_packet()? {
+        if let Some(segment) = ip::parse_ip_packet(&data) {
+            segments.push(segment);
+        }
+    }
+
+    let output = Arc::new(Output::default());
+    let (local_addr, local_port, mut translations) = match setup {
+        ReplaySetup::Listen(addr, port) => (addr, port, listen_translations(&segments, addr, port)),
+        ReplaySetup::Connect(addr, port) => {
+            // キャプチャ中でtoytcp側にあたるホストが送信したSYNを探す
+            let (syn, local_addr, _) = segments
+                .iter()
+                .find(|(p, _, dst)| {
+                    *dst == addr && p.get_dest() == port && p.get_flag() == tcpflags::SYN
+                })
+                .context("no SYN to the remote endpoint in the capture")?;
+            let translation = Translation {
+                captured_port: syn.get_src(),
+                captured_isn: Some(syn.get_seq()),
+                local_port: None,
+                local_isn: None,
+            };
+            (
+                *local_addr,
+                syn.get_src(),
+                HashMap::from([((addr, port), translation)]),
+            )
+        }
+    };
+
+    let device = ReplayDevice {
+        local_addr,
+        output: output.clone(),
+    };
+    let tcp = TCP::without_threads(device, Arc::new(VirtualClock::new()));
+    match setup {
+        ReplaySetup::Listen(addr, port) => {
+            tcp.listen(addr, port)?;
+        }
+        ReplaySetup::Connect(addr, port) => {
+            // 接続の完了は待たない。SYNはこの時点で送信されている
+            tcp.start_connect(local_port, addr, port)?;
+        }
+    }
+
+    let mut results = Vec::new();
+    output.drain_into(&mut translations, &mut results);
+    for (mut packet, src, dst) in segments {
+        let translation = match translations.get(&(src, packet.get_src())) {
+            Some(t) if dst == local_addr && packet.get_dest() == t.captured_port => t,
+            _ => continue,
+        };
+        // キャプチャ側の番号をtoytcp側の番号に書き換える
+        if let Some(port) = translation.local_port {
+            packet.set_dest(port);
+        }
+        if let (Some(captured), Some(local)) = (translation.captured_isn, translation.local_isn) {
+            if packet.get_flag() & tcpflags::ACK > 0 {
+                packet.set_ack(local + (packet.get_ack() - captured));
+            }
+        }
+        update_checksum(&mut packet, src, dst);
+        tcp.dispatch(&packet, src, dst);
+        output.drain_into(&mut translations, &mut results);
+    }
+    Ok(results)
+}
+
+// listenの場合、キャプチャ中の各接続についてtoytcp側が送信したSYN|ACKのシーケンス番号を記録する
+fn listen_translations(
+    segments: &[Segment],
+    addr: IpAddr,
+    port: u16,
+) -> HashMap<(IpAddr, u16), Translation> {
+    let mut translations = HashMap::new();
+    for (packet, src, dst) in segments {
+        if *dst == addr && packet.get_dest() == port && packet.get_flag() & tcpflags::SYN > 0 {
+            translations
+                .entry((*src, packet.get_src()))
+                .or_insert(Translation {
+                    captured_port: port,
+                    captured_isn: None,
+                    local_port: Some(port),
+                    local_isn: None,
+                });
+        }
+        if *src == addr
+            && packet.get_src() == port
+            && packet.get_flag() == tcpflags::SYN | tcpflags::ACK
+        {
+            if let Some(t) = translations.get_mut(&(*dst, packet.get_dest())) {
+                t.captured_isn.get_or_insert(packet.get_seq());
+            }
+        }
+    }
+    translations
+}
+
+fn update_checksum(packet: &mut TCPPacket, src: IpAddr, dst: IpAddr) {
+    packet.set_checksum(0);
+    let checksum = packet.compute_checksum(src, dst);
+    packet.set_checksum(checksum);
+}
+
+// 接続ごとの、キャプチャ時とtoytcpとでのポート番号と初期シーケンス番号の対応
+struct Translation {
+    captured_port: u16,
+    captured_isn: Option<SeqNum>,
+    local_port: Option<u16>,
+    local_isn: Option<SeqNum>,
+}
+
+// toytcpが送信したセグメントを溜めておく
+#[derive(Default)]
+struct Output {
+    sent: Mutex<Vec<Segment>>,
+}
+
+impl Output {
+    // 溜まっているセグメントをキャプチャ側の番号に書き戻してresultsに移す
+    // toytcpのSYNを見つけたら、その接続のポート番号と初期シーケンス番号を記録する
+    fn drain_into(
+        &self,
+        translations: &mut HashMap<(IpAddr, u16), Translation>,
+        results: &mut Vec<Segment>,
+    ) {
+        for (mut packet, src, dst) in self.sent.lock().unwrap().drain(..) {
+            if let Some(t) = translations.get_mut(&(dst, packet.get_dest())) {
+                if packet.get_flag() & tcpflags::SYN > 0 {
+                    t.local_port.get_or_insert(packet.get_src());
+                    t.local_isn.get_or_insert(packet.get_seq());
+                }
+                packet.set_src(t.captured_port);
+                if let (Some(captured), Some(local)) = (t.captured_isn, t.local_isn) {
+                    packet.set_seq(captured + (packet.get_seq() - local));
+                }
+                update_checksum(&mut packet, src, dst);
+            }
+            results.push((packet, src, dst));
+        }
+    }
+}
+
+// 送信したセグメントを記録するだけのデバイス。受信はdispatchで直接行う
+struct ReplayDevice {
+    local_addr: IpAddr,
+    output: Arc<Output>,
+}
+
+impl Device for ReplayDevice {
+    fn send(&self, packet: &TCPPacket, src: IpAddr, dst: IpAddr) -> Result<usize> {
+        self.output
+            .sent
+            .lock()
+            .unwrap()
+            .push((packet.clone(), src, dst));
+        Ok(packet.packet().len())
+    }
+
+    fn recv(&self) -> Result<(TCPPacket, IpAddr, IpAddr)> {
+        // 受信スレッドを起動しないので呼ばれない。セグメントはdispatchで直接入力する
+        anyhow::bail!("replay device does not receive from the wire")
+    }
+
+    fn source_addr_to(&self, _addr: IpAddr) -> Result<IpAddr> {
+        Ok(self.local_addr)
+    }
+}
@@ -0,0 +1,473 @@
+use std::error::Error;
+use std::ffi::CStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ptr;
+
+const IPV4_ROUTE_PATH: &str = "/proc/net/route";
+const IPV6_ROUTE_PATH: &str = "/proc/net/ipv6_route";
+const LOOPBACK_INTERFACE: &str = "lo";
+// linux/route.h
+const RTF_UP: u32 = 0x0001;
+const RTF_GATEWAY: u32 = 0x0002;
+const RTF_REJECT: u32 = 0x0200;
+
+// 宛先に対する経路の検索結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub source: IpAddr,          // 送信元として利用するローカルのアドレス
+    pub interface: String,       // 送信に利用するインタフェース
+    pub gateway: Option<IpAddr>, // 宛先が直接接続されていない場合に経由するルータ
+    pub mtu: usize,              // 経路のMTU。経路に指定がなければインタフェースのMTU
+}
+
+#[derive(Debug)]
+pub enum RouteError {
+    // 宛先への経路が存在しない、または到達不能な経路が選ばれた
+    NoRoute(IpAddr),
+    // 送信に利用するインタフェースに、宛先と同じアドレスファミリのアドレスがない
+    NoSourceAddress(String),
+    // 経路表のプレフィックス長がアドレスの長さを超えている、またはネットマスクが連続していない
+    InvalidPrefix(String),
+    // 経路表やインタフェースの情報を読み出せなかった
+    Io(io::Error),
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RouteError::NoRoute(addr) => write!(f, "no route to {}", addr),
+            RouteError::NoSourceAddress(interface) => {
+                write!(f, "no source address on {}", interface)
+            }
+            RouteError::InvalidPrefix(prefix) => {
+                write!(f, "invalid prefix in route entry: {}", prefix)
+            }
+            RouteError::Io(error) => write!(f, "failed to read routing information: {}", error),
+        }
+    }
+}
+
+impl Error for RouteError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RouteError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RouteError {
+    fn from(error: io::Error) -> Self {
+        RouteError::Io(error)
+    }
+}
+
+// 宛先に対する経路を検索する
+// カーネルのメインの経路表を/proc/net/route、/proc/net/ipv6_routeから読み出して最長一致で選び、
+// 送信元アドレスは送信インタフェースのアドレスから選ぶ。外部コマンドは使用しない。
+// ポリシールーティング(ip rule)には対応しない。
+//
+// $ sudo ip netns exec host2 ip route get 10.0.0.1
+// 10.0.0.1 via 10.0.1.254 dev host2-veth1 src 10.0.1.1 uid 0
+// に相当する結果を返す。
+pub fn lookup(dst: IpAddr) -> Result<Route, RouteError> {
+    let addrs = interface_addrs()?;
+    // 自身のアドレスやループバックアドレス宛てはloを経由する
+    if dst.is_loopback() || addrs.iter().any(|a| a.addr == dst) {
+        let source = match dst {
+            IpAddr::V4(_) if dst.is_loopback() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            _ => dst,
+        };
+        return Ok(Route {
+            source,
+            interface: LOOPBACK_INTERFACE.to_string(),
+            gateway: None,
+            mtu: interface_mtu(LOOPBACK_INTERFACE)?,
+        });
+    }
+
+    let entries = match dst {
+        IpAddr::V4(_) => read_ipv4_routes()?,
+        IpAddr::V6(_) => read_ipv6_routes()?,
+    };
+    let entry = select_route(entries, dst)?;
+
+    // 宛先とスコープが同じで、ネクストホップと同じサブネットにあるアドレスを優先する
+    let next_hop = entry.gateway.unwrap_or(dst);
+    let source = addrs
+        .iter()
+        .filter(|a| a.interface == entry.interface && a.addr.is_ipv4() == dst.is_ipv4())
+        .min_by_key(|a| {
+            (
+                is_link_local(a.addr) != is_link_local(dst),
+                !prefix_matches(a.addr, next_hop, a.prefix_len),
+            )
+        })
+        .map(|a| a.addr)
+        .ok_or_else(|| RouteError::NoSourceAddress(entry.interface.clone()))?;
+    let mtu = match entry.mtu {
+        0 => interface_mtu(&entry.interface)?,
+        mtu => mtu,
+    };
+    Ok(Route {
+        source,
+        interface: entry.interface,
+        gateway: entry.gateway,
+        mtu,
+    })
+}
+
+// プレフィックスが最も長く、同じ長さならメトリックが最も小さい経路を選ぶ
+fn select_route(entries: Vec<RouteEntry>, dst: IpAddr) -> Result<RouteEntry, RouteError> {
+    let entry = entries
+        .into_iter()
+        .filter(|e| e.flags & RTF_UP > 0 && prefix_matches(e.destination, dst, e.prefix_len))
+        .min_by_key(|e| (u32::MAX - e.prefix_len, e.metric))
+        .ok_or(RouteError::NoRoute(dst))?;
+    if entry.flags & RTF_REJECT > 0 {
+        return Err(RouteError::NoRoute(dst));
+    }
+    Ok(entry)
+}
+
+// 経路表の1エントリ
+#[derive(Debug)]
+struct RouteEntry {
+    destination: IpAddr,
+    prefix_len: u32,
+    gateway: Option<IpAddr>,
+    metric: u32,
+    flags: u32,
+    interface: String,
+    mtu: usize,
+}
+
+// /proc/net/routeの各行は以下の形式。アドレスはネットワークバイトオーダーの値を16進数で表示したもの
+// Iface Destination Gateway Flags RefCnt Use Metric Mask     MTU Window IRTT
+// eth0  0001A8C0    00000000 0001 0      0   0      00FFFFFF 0   0      0
+fn read_ipv4_routes() -> Result<Vec<RouteEntry>, RouteError> {
+    fs::read_to_string(IPV4_ROUTE_PATH)?
+        .lines()
+        .skip(1)
+        .map(|line| parse_ipv4_route(&line.split_whitespace().collect::<Vec<_>>()))
+        .collect()
+}
+
+fn parse_ipv4_route(fields: &[&str]) -> Result<RouteEntry, RouteError> {
+    if fields.len() < 9 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed route entry").into());
+    }
+    let addr = |s| u32::from_str_radix(s, 16).map(|v| Ipv4Addr::from(v.to_ne_bytes()));
+    let flags = u32::from_str_radix(fields[3], 16).map_err(invalid_data)?;
+    let gateway = addr(fields[2]).map_err(invalid_data)?;
+    // ネットマスクは上位ビットから連続して1が並んでいなければならない
+    let mask = u32::from(addr(fields[7]).map_err(invalid_data)?);
+    if mask.leading_ones() != mask.count_ones() {
+        return Err(RouteError::InvalidPrefix(fields[7].to_string()));
+    }
+    Ok(RouteEntry {
+        destination: addr(fields[1]).map_err(invalid_data)?.into(),
+        prefix_len: mask.count_ones(),
+        gateway: (flags & RTF_GATEWAY > 0).then_some(gateway.into()),
+        metric: fields[6].parse().map_err(invalid_data)?,
+        flags,
+        interface: fields[0].to_string(),
+        mtu: fields[8].parse().map_err(invalid_data)?,
+    })
+}
+
+// /proc/net/ipv6_routeの各行は以下の形式。ヘッダ行はない
+// Destination PrefixLen Source SourcePrefixLen NextHop Metric RefCnt Use Flags Iface
+fn read_ipv6_routes() -> Result<Vec<RouteEntry>, RouteError> {
+    fs::read_to_string(IPV6_ROUTE_PATH)?
+        .lines()
+        .map(|line| parse_ipv6_route(&line.split_whitespace().collect::<Vec<_>>()))
+        .collect()
+}
+
+fn parse_ipv6_route(fields: &[&str]) -> Result<RouteEntry, RouteError> {
+    if fields.len() < 10 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed route entry").into());
+    }
+    let addr = |s| u128::from_str_radix(s, 16).map(Ipv6Addr::from);
+    let flags = u32::from_str_radix(fields[8], 16).map_err(invalid_data)?;
+    let gateway = addr(fields[4]).map_err(invalid_data)?;
+    let prefix_len = u32::from_str_radix(fields[1], 16).map_err(invalid_data)?;
+    if prefix_len > 128 {
+        return Err(RouteError::InvalidPrefix(fields[1].to_string()));
+    }
+    Ok(RouteEntry {
+        destination: addr(fields[0]).map_err(invalid_data)?.into(),
+        prefix_len,
+        gateway: (flags & RTF_GATEWAY > 0).then_some(gateway.into()),
+        metric: u32::from_str_radix(fields[5], 16).map_err(invalid_data)?,
+        flags,
+        interface: fields[9].to_string(),
+        // 経路ごとのMTUは公開されていないのでインタフェースのMTUを用いる
+        mtu: 0,
+    })
+}
+
+fn invalid_data(error: std::num::ParseIntError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+// インタフェースに割り当てられたアドレス
+struct InterfaceAddr {
+    interface: String,
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+// getifaddrs(3)で全インタフェースのIPアドレスを取得する
+fn interface_addrs() -> io::Result<Vec<InterfaceAddr>> {
+    let mut ifap: *mut libc::ifaddrs = ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifap) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut addrs = Vec::new();
+    let mut cursor = ifap;
+    while !cursor.is_null() {
+        let ifa = unsafe { &*cursor };
+        cursor = ifa.ifa_next;
+        let (addr, netmask) = unsafe { (to_ip_addr(ifa.ifa_addr), to_ip_addr(ifa.ifa_netmask)) };
+        let (addr, netmask) = match (addr, netmask) {
+            (Some(addr), Some(netmask)) => (addr, netmask),
+            _ => continue,
+        };
+        let prefix_len = match netmask {
+            IpAddr::V4(mask) => u32::from(mask).count_ones(),
+            IpAddr::V6(mask) => u128::from(mask).count_ones(),
+        };
+        addrs.push(InterfaceAddr {
+            interface: unsafe { CStr::from_ptr(ifa.ifa_name) }
+                .to_string_lossy()
+                .into_owned(),
+            addr,
+            prefix_len,
+        });
+    }
+    unsafe { libc::freeifaddrs(ifap) };
+    Ok(addrs)
+}
+
+// struct sockaddrからIPアドレスを取り出す。IP以外のアドレスファミリはNoneになる
+unsafe fn to_ip_addr(sa: *const libc::sockaddr) -> Option<IpAddr> {
+    if sa.is_null() {
+        return None;
+    }
+    match (*sa).sa_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = &*(sa as *const libc::sockaddr_in);
+            Some(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)).into())
+        }
+        libc::AF_INET6 => {
+            let sin6 = &*(sa as *const libc::sockaddr_in6);
+            Some(Ipv6Addr::from(sin6.sin6_addr.s6_addr).into())
+        }
+        _ => None,
+    }
+}
+
+fn interface_mtu(interface: &str) -> io::Result<usize> {
+    fs::read_to_string(format!("/sys/class/net/{}/mtu", interface))?
+        .trim()
+        .parse()
+        .map_err(invalid_data)
+}
+
+// aとbの先頭prefix_lenビットが一致するか
+fn prefix_matches(a: IpAddr, b: IpAddr, prefix_len: u32) -> bool {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+            u32::from(a) & mask == u32::from(b) & mask
+        }
+        (IpAddr::V6(a), IpAddr::V6(b)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+            u128::from(a) & mask == u128::from(b) & mask
+        }
+        _ => false,
+    }
+}
+
+fn is_link_local(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => addr.is_link_local(),
+        IpAddr::V6(addr) => addr.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // /proc/net/routeの値はホストのバイトオーダーで表示される。以下はリトルエンディアンの場合
+    const IPV4_ROUTES: &str = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t00000000\tFE01000A\t0003\t0\t0\t100\t00000000\t0\t0\t0
+eth0\t0001000A\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0
+eth1\t0000000A\t00000000\t0001\t0\t0\t0\t0000FFFF\t1400\t0\t0
+eth1\t0002000A\t00000000\t0201\t0\t0\t0\t00FFFFFF\t0\t0\t0
+";
+
+    const IPV6_ROUTES: &str = "\
+fd000000000000000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001 eth0
+fd000000000000010000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001 eth1
+fd000000000000010000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000400 00000001 00000000 00000001 eth0
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 fd0000000000000000000000000000fe 00000400 00000001 00000000 00000003 eth0
+";
+
+    fn fields(line: &str) -> Vec<&str> {
+        line.split_whitespace().collect()
+    }
+
+    fn ipv4_routes() -> Vec<RouteEntry> {
+        IPV4_ROUTES
+            .lines()
+            .skip(1)
+            .map(|line| parse_ipv4_route(&fields(line)).unwrap())
+            .collect()
+    }
+
+    fn ipv6_routes() -> Vec<RouteEntry> {
+        IPV6_ROUTES
+            .lines()
+            .map(|line| parse_ipv6_route(&fields(line)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn parse_ipv4() {
+        let routes = ipv4_routes();
+        // (宛先, プレフィックス長, ゲートウェイ, MTU)
+        let expected = [
+            ("0.0.0.0", 0, Some("10.0.1.254"), 0),
+            ("10.0.1.0", 24, None, 0),
+            ("10.0.0.0", 16, None, 1400),
+            ("10.0.2.0", 24, None, 0),
+        ];
+        assert_eq!(routes.len(), expected.len());
+        for (route, (destination, prefix_len, gateway, mtu)) in routes.iter().zip(expected) {
+            assert_eq!(route.destination, destination.parse::<IpAddr>().unwrap());
+            assert_eq!(route.prefix_len, prefix_len);
+            assert_eq!(route.gateway, gateway.map(|g| g.parse().unwrap()));
+            assert_eq!(route.mtu, mtu);
+        }
+    }
+
+    #[test]
+    fn parse_ipv6() {
+        let routes = ipv6_routes();
+        // (宛先, プレフィックス長, ゲートウェイ, メトリック)
+        let expected = [
+            ("fd00::", 64, None, 0x100),
+            ("fd00:0:0:1::", 64, None, 0x100),
+            ("fd00:0:0:1::", 64, None, 0x400),
+            ("::", 0, Some("fd00::fe"), 0x400),
+        ];
+        assert_eq!(routes.len(), expected.len());
+        for (route, (destination, prefix_len, gateway, metric)) in routes.iter().zip(expected) {
+            assert_eq!(route.destination, destination.parse::<IpAddr>().unwrap());
+            assert_eq!(route.prefix_len, prefix_len);
+            assert_eq!(route.gateway, gateway.map(|g| g.parse().unwrap()));
+            assert_eq!(route.metric, metric);
+        }
+    }
+
+    #[test]
+    fn reject_invalid_prefix() {
+        let invalid_ipv4 = [
+            // 連続していないネットマスク
+            "eth0\t0001000A\t00000000\t0001\t0\t0\t0\t00FF00FF\t0\t0\t0",
+            "eth0\t0001000A\t00000000\t0001\t0\t0\t0\tFFFFFF00\t0\t0\t0",
+        ];
+        for line in invalid_ipv4 {
+            assert!(matches!(
+                parse_ipv4_route(&fields(line)),
+                Err(RouteError::InvalidPrefix(_))
+            ));
+        }
+        // プレフィックス長が128を超える
+        let line = "fd000000000000000000000000000000 81 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001 eth0";
+        assert!(matches!(
+            parse_ipv6_route(&fields(line)),
+            Err(RouteError::InvalidPrefix(_))
+        ));
+        // プレフィックス長がちょうど128なら受け付ける
+        let line = line.replacen(" 81 ", " 80 ", 1);
+        assert_eq!(parse_ipv6_route(&fields(&line)).unwrap().prefix_len, 128);
+    }
+
+    #[test]
+    fn select_longest_prefix() {
+        // (宛先, 選ばれるインタフェース, ゲートウェイ)。Noneは経路がないことを表す
+        let ipv4 = [
+            ("10.0.1.1", Some(("eth0", None))),
+            ("10.0.3.1", Some(("eth1", None))),
+            ("192.168.0.1", Some(("eth0", Some("10.0.1.254")))),
+            // 到達不能な経路が最長一致する
+            ("10.0.2.1", None),
+        ];
+        for (dst, expected) in ipv4 {
+            check_route(ipv4_routes(), dst, expected);
+        }
+        // 同じプレフィックス長ならメトリックが小さい経路を選ぶ
+        let ipv6 = [
+            ("fd00::1", Some(("eth0", None))),
+            ("fd00:0:0:1::1", Some(("eth1", None))),
+            ("2001:db8::1", Some(("eth0", Some("fd00::fe")))),
+        ];
+        for (dst, expected) in ipv6 {
+            check_route(ipv6_routes(), dst, expected);
+        }
+        // デフォルト経路がなければ経路は見つからない
+        let routes = ipv4_routes().into_iter().skip(1).collect();
+        check_route(routes, "192.168.0.1", None);
+    }
+
+    fn check_route(routes: Vec<RouteEntry>, dst: &str, expected: Option<(&str, Option<&str>)>) {
+        let dst: IpAddr = dst.parse().unwrap();
+        match (select_route(routes, dst), expected) {
+            (Ok(route), Some((interface, gateway))) => {
+                assert_eq!(route.interface, interface, "{}", dst);
+                assert_eq!(
+                    route.gateway,
+                    gateway.map(|g| g.parse().unwrap()),
+                    "{}",
+                    dst
+                );
+            }
+            (Err(RouteError::NoRoute(addr)), None) => assert_eq!(addr, dst),
+            (result, _) => panic!("unexpected route to {}: {:?}", dst, result),
+        }
+    }
+
+    #[test]
+    fn prefix_boundaries() {
+        let addr = |s: &str| s.parse::<IpAddr>().unwrap();
+        let cases = [
+            ("10.0.0.1", "192.168.0.1", 0, true),
+            ("10.0.0.1", "10.0.0.1", 32, true),
+            ("10.0.0.1", "10.0.0.2", 32, false),
+            ("10.0.0.1", "10.0.0.2", 30, true),
+            ("fd00::1", "2001:db8::1", 0, true),
+            ("fd00::1", "fd00::1", 128, true),
+            ("fd00::1", "fd00::2", 128, false),
+            ("fd00::1", "10.0.0.1", 0, false),
+        ];
+        for (a, b, prefix_len, expected) in cases {
+            assert_eq!(
+                prefix_matches(addr(a), addr(b), prefix_len),
+                expected,
+                "{} {} /{}",
+                a,
+                b,
+                prefix_len
+            );
+        }
+    }
+}
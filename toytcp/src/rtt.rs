@@ -0,0 +1,134 @@
+use std::cmp;
+use std::time::Duration;
+
+// 再送タイムアウト(RTO)の計算
+// https://datatracker.ietf.org/doc/html/rfc6298
+//
+// 確認応答までの時間(RTT)を測定するたびに、平滑化したRTT(SRTT)とそのばらつき(RTTVAR)を更新する
+//   RTTVAR <- (1 - beta) * RTTVAR + beta * |SRTT - R'|
+//   SRTT <- (1 - alpha) * SRTT + alpha * R'
+//   RTO <- SRTT + max (G, K*RTTVAR)
+// alpha = 1/8, beta = 1/4, K = 4、Gはタイマーの粒度
+
+// RTTを測定する前のRTO
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+// RFC 6298では下限を1秒としているが、遅延の小さいネットワークで再送が遅れすぎるのでLinuxと同じ200msにする
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(60);
+// タイマースレッドが再送キューを確認する間隔
+pub const CLOCK_GRANULARITY: Duration = Duration::from_millis(100);
+const K: u32 = 4;
+
+#[derive(Clone, Debug)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+        }
+    }
+
+    // 測定したRTTでSRTT、RTTVARとRTOを更新する
+    // 再送したセグメントのRTTは元の送信と再送のどちらに対するACKか区別できないので、
+    // 呼び出し側で測定から除外すること(Karnのアルゴリズム)
+    pub fn update(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = self.rttvar * 3 / 4 + srtt.abs_diff(rtt) / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap();
+        self.rto = (srtt + cmp::max(CLOCK_GRANULARITY, self.rttvar * K)).clamp(MIN_RTO, MAX_RTO);
+        dbg!("rtt sample", rtt, srtt, self.rto);
+    }
+
+    // 再送タイムアウトが発生した時にRTOを2倍にする(指数バックオフ)
+    // 次にRTTを測定した時点でSRTTとRTTVARから計算し直される
+    pub fn backoff(&mut self) {
+        self.rto = cmp::min(self.rto * 2, MAX_RTO);
+    }
+
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    // RTTを測定するまではNone
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_sample_initializes_srtt_and_rttvar() {
+        let mut rtt = RttEstimator::new();
+        assert_eq!(rtt.srtt(), None);
+        assert_eq!(rtt.rto(), INITIAL_RTO);
+        rtt.update(Duration::from_millis(400));
+        assert_eq!(rtt.srtt(), Some(Duration::from_millis(400)));
+        assert_eq!(rtt.rttvar(), Duration::from_millis(200));
+        // SRTT + 4 * RTTVAR
+        assert_eq!(rtt.rto(), Duration::from_millis(1200));
+    }
+
+    #[test]
+    fn later_samples_are_smoothed() {
+        let mut rtt = RttEstimator::new();
+        rtt.update(Duration::from_millis(400));
+        rtt.update(Duration::from_millis(800));
+        // RTTVAR = 3/4 * 200 + 1/4 * |400 - 800|、SRTT = 7/8 * 400 + 1/8 * 800
+        assert_eq!(rtt.rttvar(), Duration::from_millis(250));
+        assert_eq!(rtt.srtt(), Some(Duration::from_millis(450)));
+        assert_eq!(rtt.rto(), Duration::from_millis(1450));
+    }
+
+    #[test]
+    fn rto_is_clamped() {
+        let mut rtt = RttEstimator::new();
+        rtt.update(Duration::from_millis(1));
+        // ばらつきが小さくてもタイマーの粒度、さらにMIN_RTOを下回らない
+        assert_eq!(rtt.rto(), MIN_RTO);
+        rtt.update(Duration::from_secs(100));
+        assert_eq!(rtt.rto(), MAX_RTO);
+    }
+
+    #[test]
+    fn backoff_doubles_until_max_and_resets_on_sample() {
+        let mut rtt = RttEstimator::new();
+        rtt.backoff();
+        assert_eq!(rtt.rto(), INITIAL_RTO * 2);
+        for _ in 0..10 {
+            rtt.backoff();
+        }
+        assert_eq!(rtt.rto(), MAX_RTO);
+        // バックオフしてもSRTTは変わらず、次の測定でRTOが計算し直される
+        assert_eq!(rtt.srtt(), None);
+        rtt.update(Duration::from_millis(400));
+        assert_eq!(rtt.rto(), Duration::from_millis(1200));
+    }
+}
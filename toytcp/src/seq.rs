@@ -0,0 +1,128 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, AddAssign, Sub};
+
+// TCPのシーケンス番号(確認応答番号)
+// 32ビットで折り返すため、大小関係はRFC 1982のシリアル番号演算で判定する
+// https://datatracker.ietf.org/doc/html/rfc1982
+//
+// aとbの差(b - a mod 2^32)が2^31未満であれば、bの方が大きいとみなす。
+// ちょうど2^31離れている場合は大小を決められないので、比較の結果はfalseになる
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct SeqNum(u32);
+
+impl SeqNum {
+    pub const fn new(value: u32) -> Self {
+        Self(value)
+    }
+
+    pub const fn value(self) -> u32 {
+        self.0
+    }
+
+    // start <= self < end の範囲にあるか。受信ウィンドウ内の判定などに用いる
+    pub fn in_range(self, start: SeqNum, end: SeqNum) -> bool {
+        self - start < end - start
+    }
+}
+
+impl PartialOrd for SeqNum {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match self.0.wrapping_sub(other.0) {
+            0 => Some(Ordering::Equal),
+            diff if diff < 1 << 31 => Some(Ordering::Greater),
+            diff if diff > 1 << 31 => Some(Ordering::Less),
+            _ => None,
+        }
+    }
+}
+
+impl Add<u32> for SeqNum {
+    type Output = SeqNum;
+
+    fn add(self, rhs: u32) -> SeqNum {
+        SeqNum(self.0.wrapping_add(rhs))
+    }
+}
+
+impl AddAssign<u32> for SeqNum {
+    fn add_assign(&mut self, rhs: u32) {
+        self.0 = self.0.wrapping_add(rhs);
+    }
+}
+
+impl Sub<u32> for SeqNum {
+    type Output = SeqNum;
+
+    fn sub(self, rhs: u32) -> SeqNum {
+        SeqNum(self.0.wrapping_sub(rhs))
+    }
+}
+
+// rhsからselfまでのバイト数。self >= rhsの場合にのみ意味を持つ
+impl Sub<SeqNum> for SeqNum {
+    type Output = u32;
+
+    fn sub(self, rhs: SeqNum) -> u32 {
+        self.0.wrapping_sub(rhs.0)
+    }
+}
+
+impl fmt::Debug for SeqNum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for SeqNum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_across_wraparound() {
+        let before = SeqNum::new(u32::MAX - 10);
+        let after = SeqNum::new(10);
+        assert!(before < after);
+        assert!(after > before);
+        assert_eq!(before.partial_cmp(&before), Some(Ordering::Equal));
+        // 2^31以上離れていると、数値が大きい方が小さいとみなされる
+        assert!(SeqNum::new(0) > SeqNum::new(u32::MAX / 2 + 2));
+    }
+
+    #[test]
+    fn compare_at_half_range_is_undefined() {
+        let a = SeqNum::new(0);
+        let b = SeqNum::new(1 << 31);
+        assert_eq!(a.partial_cmp(&b), None);
+        assert_eq!((a < b, a > b, a == b), (false, false, false));
+    }
+
+    #[test]
+    fn arithmetic_wraps() {
+        let mut seq = SeqNum::new(u32::MAX - 1);
+        assert_eq!(seq + 3, SeqNum::new(1));
+        assert_eq!(SeqNum::new(1) - 3, SeqNum::new(u32::MAX - 1));
+        assert_eq!(SeqNum::new(1) - seq, 3);
+        seq += 2;
+        assert_eq!(seq, SeqNum::new(0));
+    }
+
+    #[test]
+    fn in_range_across_wraparound() {
+        let start = SeqNum::new(u32::MAX - 100);
+        let end = start + 200;
+        assert!(start.in_range(start, end));
+        assert!(SeqNum::new(0).in_range(start, end));
+        assert!(SeqNum::new(98).in_range(start, end));
+        assert!(!end.in_range(start, end));
+        assert!(!(start - 1).in_range(start, end));
+        // 空の範囲には何も含まれない
+        assert!(!start.in_range(start, start));
+    }
+}
@@ -0,0 +1,77 @@
+use crate::device::Device;
+use crate::packet::TCPPacket;
+use anyhow::{Context, Result};
+use pnet::packet::Packet;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+// ネットワーク上を流れるセグメント。(セグメント, 送信元アドレス, 宛先アドレス)
+type Segment = (TCPPacket, IpAddr, IpAddr);
+
+// プロセス内で完結する仮想ネットワーク
+// 接続された各ホストはIPアドレスを持ち、チャネルを介してセグメントを交換する。
+// RAWソケットを使わないので特権なしで複数のTCPインスタンスを通信させることができる。
+//
+// let network = SimNetwork::new();
+// let server = TCP::with_device(network.attach("10.0.0.1".parse()?)?);
+// let client = TCP::with_device(network.attach("10.0.1.1".parse()?)?);
+#[derive(Clone, Default)]
+pub struct SimNetwork {
+    // アドレスから、そのアドレスを持つホストの受信キューへの対応
+    hosts: Arc<Mutex<HashMap<IpAddr, Sender<Segment>>>>,
+}
+
+impl SimNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 指定したアドレスを持つホストをネットワークに接続し、そのホスト用のデバイスを返す
+    pub fn attach(&self, addr: IpAddr) -> Result<SimDevice> {
+        let mut hosts = self.hosts.lock().unwrap();
+        if hosts.contains_key(&addr) {
+            anyhow::bail!("address already in use: {}", addr);
+        }
+        let (sender, receiver) = mpsc::channel();
+        hosts.insert(addr, sender);
+        Ok(SimDevice {
+            addr,
+            network: self.clone(),
+            receiver: Mutex::new(receiver),
+        })
+    }
+}
+
+// SimNetworkに接続されたホストのデバイス
+pub struct SimDevice {
+    addr: IpAddr,
+    network: SimNetwork,
+    receiver: Mutex<Receiver<Segment>>,
+}
+
+impl Device for SimDevice {
+    fn send(&self, packet: &TCPPacket, src: IpAddr, dst: IpAddr) -> Result<usize> {
+        let hosts = self.network.hosts.lock().unwrap();
+        // 宛先のホストが存在しない場合は実際のネットワークと同様にセグメントは失われる
+        if let Some(host) = hosts.get(&dst) {
+            // 宛先のホストが既に破棄されている場合も同様
+            let _ = host.send((packet.clone(), src, dst));
+        }
+        Ok(packet.packet().len())
+    }
+
+    fn recv(&self) -> Result<(TCPPacket, IpAddr, IpAddr)> {
+        self.receiver
+            .lock()
+            .unwrap()
+            .recv()
+            .context("simulated network is disconnected")
+    }
+
+    fn source_addr_to(&self, _addr: IpAddr) -> Result<IpAddr> {
+        // 各ホストはアドレスを一つだけ持つ
+        Ok(self.addr)
+    }
+}
@@ -1,15 +1,26 @@
-use crate::packet::TCPPacket;
+use crate::clock::Clock;
+use crate::congestion::{CongestionControl, CongestionWindow, NewReno};
+use crate::device::Device;
+use crate::packet::{TCPPacket, TCPPacketBuilder};
+use crate::rate::{DeliveryRateEstimator, DeliveryState};
+use crate::reassembly::ReassemblyQueue;
+use crate::rtt::RttEstimator;
+use crate::seq::SeqNum;
+use crate::stats::SocketStats;
+use crate::tcp::{DEFAULT_SEND_MSS, IPV6_MSS, MSS};
 use crate::tcpflags;
-use anyhow::{Context, Result};
-use pnet::packet::{ip::IpNextHeaderProtocols, Packet};
-use pnet::transport::{self, TransportChannelType, TransportProtocol, TransportSender};
-use pnet::util;
+use crate::tcpoption::TcpOption;
+use anyhow::Result;
+use std::cmp;
 use std::collections::VecDeque;
 use std::fmt::{self, Debug};
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::IpAddr;
+use std::sync::Arc;
 use std::time::SystemTime;
 
-const SOCKET_BUFFER_SIZE: usize = 4380;
+// MSSの44倍。送信量はmin(cwnd, rwnd)で制限されるため、受信ウィンドウが小さいと輻輳ウィンドウを増やしても効果がない
+// 輻輳制御の動作を確認できるよう、Linuxの初期受信ウィンドウと同じ大きさにする
+const SOCKET_BUFFER_SIZE: usize = 64240;
 
 // TCPソケット状態遷移
 // https://datatracker.ietf.org/doc/html/rfc793
@@ -61,11 +72,11 @@ const SOCKET_BUFFER_SIZE: usize = 4380;
 
 // (local_addr, remote_addr, local_port, remote_port)のタプルでソケットを識別する
 #[derive(Debug, Hash, Eq, PartialEq, Clone, Copy)]
-pub struct SockID(pub Ipv4Addr, pub Ipv4Addr, pub u16, pub u16);
+pub struct SockID(pub IpAddr, pub IpAddr, pub u16, pub u16);
 
-pub struct Socket {
-    pub local_addr: Ipv4Addr,
-    pub remote_addr: Ipv4Addr,
+pub struct Socket<D: Device> {
+    pub local_addr: IpAddr,
+    pub remote_addr: IpAddr,
     pub local_port: u16,
     pub remote_port: u16,
 
@@ -79,31 +90,68 @@ pub struct Socket {
     pub status: TcpStatus,
     pub recv_buffer: Vec<u8>,
 
+    // 受信バッファ内の、順序が入れ替わって届いたデータの範囲を管理するキュー
+    pub reassembly_queue: ReassemblyQueue,
+
     // 再送用のセグメントを保管するキュー
     pub retransmission_queue: VecDeque<RetransmissionQueueEntry>,
 
+    // 確認応答までの時間から再送タイムアウトを求める
+    pub rtt: RttEstimator,
+
+    // 続けて受信した重複ACKの数
+    pub dup_ack_count: u32,
+
+    // 高速リカバリ中であれば、開始時点のSND.NXT(リカバリポイント)。ここまでACKされたら終了する
+    pub recovery_point: Option<SeqNum>,
+
+    // 輻輳ウィンドウと、それを調整する輻輳制御アルゴリズム
+    pub congestion_window: CongestionWindow,
+    pub congestion_control: Box<dyn CongestionControl>,
+
+    // 送信したセグメントのACKから配送レートを測定する
+    pub delivery_rate: DeliveryRateEstimator,
+
+    // ペーシングで次のセグメントを送信できる時刻。送信レートが指定されていなければNone
+    pub next_send_time: Option<SystemTime>,
+
+    // 統計情報用のカウンタ。ACKされたバイト数と再送したセグメント数
+    pub bytes_acked: u64,
+    pub retransmissions: u64,
+
     // 接続済みソケットを保持するキュー。リスニングソケットのみ使用
     pub connected_connection_queue: VecDeque<SockID>,
 
     // 生成元のリスニングソケット。接続済みソケットのみ使用
     pub listening_socket: Option<SockID>,
-    pub sender: TransportSender,
+
+    // TIMEWAIT状態に入った時刻。2MSL経過後にタイマースレッドがソケットを破棄する
+    pub time_wait_started: Option<SystemTime>,
+
+    // セグメントの送信に利用するバックエンド。TCPインスタンス内の全ソケットで共有する
+    pub sender: Arc<D>,
+
+    // 再送キューのエントリに送信時刻を記録するための時計
+    pub clock: Arc<dyn Clock>,
 }
 
 // タイムアウト判定のために最終送信時刻と送信回数が保存される。
+// 配送レートの測定のために、最後に送信した時点の配送状況も保存する
 #[derive(Clone, Debug)]
 pub struct RetransmissionQueueEntry {
     pub packet: TCPPacket,
     pub latest_transmission_time: SystemTime,
     pub transmission_count: u8,
+    pub delivery: DeliveryState,
 }
 
 impl RetransmissionQueueEntry {
-    fn new(packet: TCPPacket) -> Self {
+    fn new(packet: TCPPacket, delivery: DeliveryState) -> Self {
         Self {
             packet,
-            latest_transmission_time: SystemTime::now(),
+            latest_transmission_time: delivery.sent_time,
             transmission_count: 1,
+            delivery,
         }
     }
 }
@@ -119,10 +167,10 @@ impl RetransmissionQueueEntry {
 // 4 - まだ送信不可能
 #[derive(Clone, Debug)]
 pub struct SendParam {
-    pub unacked_seq: u32, // 送信後まだackされていないseqの先頭
-    pub next: u32,        // 次の送信seq
-    pub window: u16,      // 送信ウィンドウサイズ
-    pub initial_seq: u32, // 初期送信seq
+    pub unacked_seq: SeqNum, // 送信後まだackされていないseqの先頭
+    pub next: SeqNum,        // 次の送信seq
+    pub window: u16,         // 相手の受信ウィンドウサイズ
+    pub initial_seq: SeqNum, // 初期送信seq
 }
 
 // SnedParam構造体パラメータの位置関係
@@ -135,16 +183,16 @@ pub struct SendParam {
 // 3 - まだ受信受け入れ不可能
 #[derive(Clone, Debug)]
 pub struct RecvParam {
-    pub next: u32,        // 次受信するseq
-    pub window: u16,      // 受信ウィンドウサイズ
-    pub initial_seq: u32, // 初期受信seq
-    pub tail: u32,        // 受信seqの末尾
+    pub next: SeqNum,        // 次受信するseq
+    pub window: u16,         // 受信ウィンドウサイズ
+    pub initial_seq: SeqNum, // 初期受信seq
 }
 
 // CLOSEDの状態からESTAへと遷移するには２通りの方法がある。
 // - アクティブオープン：通信相手のホストへ最初にSYNセグメントを送信し、能動的にコネクションを確立する方法
 // - パッシブオープン：通信相手のホストから最初にSYNセグメントを受け入れ、受動的にコネクションを確立する方法
 // 一般的なWebサーバはパッシブオープンを、クライアントとなるブラウザはそれに対してアクティブオープンを行う。
+#[derive(PartialEq)]
 pub enum TcpStatus {
     Listen,
     SynSent,
@@ -152,6 +200,7 @@ pub enum TcpStatus {
     Established,
     FinWait1,
     FinWait2,
+    Closing,
     TimeWait,
     CloseWait,
     LastAck,
@@ -166,6 +215,7 @@ impl Debug for TcpStatus {
             TcpStatus::Established => write!(f, "ESTABLISHED"),
             TcpStatus::FinWait1 => write!(f, "FINWAIT1"),
             TcpStatus::FinWait2 => write!(f, "FINWAIT2"),
+            TcpStatus::Closing => write!(f, "CLOSING"),
             TcpStatus::TimeWait => write!(f, "TIMEWAIT"),
             TcpStatus::CloseWait => write!(f, "CLOSEWAIT"),
             TcpStatus::LastAck => write!(f, "LASTACK"),
@@ -173,83 +223,149 @@ impl Debug for TcpStatus {
     }
 }
 
-impl Socket {
+// 自分が受信できるセグメントの最大サイズ。IPのヘッダ長はアドレスファミリによって異なる
+fn local_mss(local_addr: IpAddr) -> usize {
+    match local_addr {
+        IpAddr::V4(_) => MSS,
+        IpAddr::V6(_) => IPV6_MSS,
+    }
+}
+
+impl<D: Device> Socket<D> {
     pub fn new(
-        local_addr: Ipv4Addr,
-        remote_addr: Ipv4Addr,
+        local_addr: IpAddr,
+        remote_addr: IpAddr,
         local_port: u16,
         remote_port: u16,
-        status: TcpStatus
+        status: TcpStatus,
+        sender: Arc<D>,
+        clock: Arc<dyn Clock>,
     ) -> Result<Self> {
-        let (sender, _) = transport::transport_channel(
-            65535,
-            TransportChannelType::Layer4(TransportProtocol::Ipv4(IpNextHeaderProtocols::Tcp)),
-        )?;
         Ok(Self {
             local_addr,
             remote_addr,
             local_port,
             remote_port,
             send_param: SendParam {
-                unacked_seq: 0,
-                initial_seq: 0,
-                next: 0,
+                unacked_seq: SeqNum::default(),
+                initial_seq: SeqNum::default(),
+                next: SeqNum::default(),
                 window: SOCKET_BUFFER_SIZE as u16,
             },
             recv_param: RecvParam {
-                initial_seq: 0,
-                next: 0,
+                initial_seq: SeqNum::default(),
+                next: SeqNum::default(),
                 window: SOCKET_BUFFER_SIZE as u16,
-                tail: 0,
             },
             status,
             recv_buffer: vec![0; SOCKET_BUFFER_SIZE],
+            reassembly_queue: ReassemblyQueue::new(),
             retransmission_queue: VecDeque::new(),
+            rtt: RttEstimator::new(),
+            dup_ack_count: 0,
+            recovery_point: None,
+            congestion_window: CongestionWindow::new(local_mss(local_addr)),
+            congestion_control: Box::new(NewReno::new()),
+            delivery_rate: DeliveryRateEstimator::new(clock.now()),
+            next_send_time: None,
+            bytes_acked: 0,
+            retransmissions: 0,
             connected_connection_queue: VecDeque::new(),
             listening_socket: None,
+            time_wait_started: None,
             sender,
+            clock,
         })
     }
 
     pub fn send_tcp_packet(
         &mut self,
-        seq: u32,
-        ack: u32,
+        seq: SeqNum,
+        ack: SeqNum,
         flag: u8,
         payload: &[u8]
     ) -> Result<usize> {
-        let mut tcp_packet = TCPPacket::new(payload.len());
-        tcp_packet.set_src(self.local_port);
-        tcp_packet.set_dest(self.remote_port);
-        tcp_packet.set_seq(seq);
-        tcp_packet.set_ack(ack);
-        tcp_packet.set_data_offset(5);
-        tcp_packet.set_flag(flag);
-        tcp_packet.set_window_size(self.recv_param.window);
-        tcp_packet.set_payload(payload);
-        tcp_packet.set_checksum(util::ipv4_checksum(
-            &tcp_packet.packet(),
-            8,
-            &[],
-            &self.local_addr,
-            &self.remote_addr,
-            IpNextHeaderProtocols::Tcp,
-        ));
+        let mut builder = TCPPacketBuilder::new()
+            .src(self.local_port)
+            .dest(self.remote_port)
+            .seq(seq)
+            .ack(ack)
+            .flag(flag)
+            .window_size(self.recv_param.window)
+            .payload(payload);
+        // SYNでは受信できるセグメントの最大サイズを通知する。省略すると相手は536バイトとみなす
+        if flag & tcpflags::SYN > 0 {
+            builder = builder.option(TcpOption::Mss(local_mss(self.local_addr) as u16));
+        }
+        let tcp_packet = builder.build(self.local_addr, self.remote_addr)?;
         let sent_size = self
             .sender
-            .send_to(tcp_packet.clone(), IpAddr::V4(self.remote_addr))
-            .context(format!("failed to send: \n{:?}", tcp_packet))?;
+            .send(&tcp_packet, self.local_addr, self.remote_addr)?;
         dbg!("sent", &tcp_packet);
         // 単純な確認応答のようなペイロードを持たないACKセグメントは再送対象にならない.
         // ∵ ACKセグメントのを再送しようとするとそのACKセグメントが必要になり、そのまたACKセグメントが...となってしまうため
         if payload.is_empty() && tcp_packet.get_flag() == tcpflags::ACK {
             return Ok(sent_size);
         }
+        let delivery = self
+            .delivery_rate
+            .on_send(self.flight_size(), self.clock.now());
         self.retransmission_queue
-            .push_back(RetransmissionQueueEntry::new(tcp_packet));
+            .push_back(RetransmissionQueueEntry::new(tcp_packet, delivery));
         Ok(sent_size)
     }
 
+    // SYNで相手が通知したMSSを、送信するセグメントの最大サイズにする
+    // 初期ウィンドウはMSSから決まるので、輻輳ウィンドウも初期化し直す
+    pub fn set_send_mss(&mut self, packet: &TCPPacket) {
+        let peer_mss = packet
+            .options()
+            .iter()
+            .find_map(|option| match option {
+                TcpOption::Mss(mss) => Some(*mss as usize),
+                _ => None,
+            })
+            .unwrap_or(DEFAULT_SEND_MSS);
+        let mss = cmp::min(peer_mss, local_mss(self.local_addr));
+        dbg!("send mss", mss);
+        self.congestion_window = CongestionWindow::new(mss);
+    }
+
+    // 送信したがまだACKされていないバイト数
+    pub fn flight_size(&self) -> usize {
+        (self.send_param.next - self.send_param.unacked_seq) as usize
+    }
+
+    // 新たに送信できるバイト数
+    // 輻輳ウィンドウと相手の受信ウィンドウの小さい方から、送信中のバイト数を引いたもの
+    pub fn usable_window(&self) -> usize {
+        cmp::min(self.congestion_window.cwnd, self.send_param.window as usize)
+            .saturating_sub(self.flight_size())
+    }
+
+    pub fn stats(&self) -> SocketStats {
+        SocketStats {
+            state: format!("{:?}", self.status),
+            congestion_control: self.congestion_control.name(),
+            cwnd: self.congestion_window.cwnd,
+            ssthresh: self.congestion_window.ssthresh,
+            mss: self.congestion_window.mss,
+            flight_size: self.flight_size(),
+            send_window: self.send_param.window as usize,
+            srtt: self.rtt.srtt(),
+            rttvar: self.rtt.rttvar(),
+            rto: self.rtt.rto(),
+            delivery_rate: self
+                .delivery_rate
+                .last_sample()
+                .and_then(|sample| sample.delivery_rate),
+            pacing_rate: self.congestion_control.pacing_rate(),
+            bytes_acked: self.bytes_acked,
+            retransmissions: self.retransmissions,
+            congestion: self.congestion_control.info(),
+        }
+    }
+
     pub fn get_sock_id(&self) -> SockID {
         SockID(
             self.local_addr,
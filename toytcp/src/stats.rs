@@ -0,0 +1,22 @@
+use crate::congestion::CongestionInfo;
+use std::time::Duration;
+
+// ソケットごとの統計情報。LinuxのTCP_INFOに相当する
+#[derive(Clone, Debug)]
+pub struct SocketStats {
+    pub state: String,                    // コネクションの状態(ESTABLISHEDなど)
+    pub congestion_control: &'static str, // 輻輳制御アルゴリズムの名前
+    pub cwnd: usize,                      // 輻輳ウィンドウ(バイト)
+    pub ssthresh: usize,                  // スロースタートの閾値(バイト)
+    pub mss: usize,
+    pub flight_size: usize,     // 送信したがまだACKされていないバイト数
+    pub send_window: usize,     // 相手の受信ウィンドウ
+    pub srtt: Option<Duration>, // 平滑化したRTT。まだ測定していなければNone
+    pub rttvar: Duration,
+    pub rto: Duration,
+    pub delivery_rate: Option<u64>, // 最後に測定した配送レート(バイト/秒)
+    pub pacing_rate: Option<u64>,   // 輻輳制御アルゴリズムが指定した送信レート(バイト/秒)
+    pub bytes_acked: u64,           // これまでにACKされたバイト数
+    pub retransmissions: u64,       // これまでに再送したセグメント数
+    pub congestion: CongestionInfo, // 輻輳制御アルゴリズム固有の状態
+}
@@ -1,41 +1,81 @@
-use crate::packet::TCPPacket;
+use crate::clock::{Clock, SystemClock};
+use crate::congestion::{AckSample, CongestionControl, NewReno};
+use crate::device::{Device, PnetDevice};
+use crate::packet::{TCPPacket, TCPPacketBuilder};
+use crate::rate::DeliveryState;
+use crate::rtt::CLOCK_GRANULARITY;
+use crate::seq::SeqNum;
 use crate::socket::{SockID, Socket, TcpStatus};
+use crate::stats::SocketStats;
 use crate::tcpflags;
 use anyhow::{Context, Result};
-use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, Packet};
-use pnet::transport::{self, TransportChannelType};
+use pnet::packet::Packet;
 use rand::{rngs::ThreadRng, Rng};
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
-use std::process::Command;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockWriteGuard};
-use std::time::{Duration, SystemTime};
-use std::{cmp, ops::Range, str, thread};
+use std::time::Duration;
+use std::{cmp, ops::Range, thread};
 
-const UNDETERMINED_IP_ADDR: std::net::Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
+const UNDETERMINED_IPV4_ADDR: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
+const UNDETERMINED_IPV6_ADDR: Ipv6Addr = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0);
 const UNDETERMINED_PORT: u16 = 0;
 const MAX_TRANSMITTION: u8 = 5;
-const RETRANSMITTION_TIMEOUT: u64 = 3;
-const MSS: usize = 1460;
+// 高速再送を行う重複ACKの数
+const DUP_ACK_THRESHOLD: u32 = 3;
+// 受信できるセグメントの最大サイズ。MTUが1500バイトの経路で、IPとTCPのヘッダを除いた大きさ
+pub(crate) const MSS: usize = 1460;
+// IPv6のヘッダはIPv4より20バイト長い
+pub(crate) const IPV6_MSS: usize = 1440;
+// 相手がMSSオプションを送ってこなかった場合に仮定するMSS(RFC 1122 4.2.2.6)
+pub(crate) const DEFAULT_SEND_MSS: usize = 536;
+// セグメントがネットワーク上に存在しうる最大時間(Maximum Segment Lifetime)の既定値
+// RFC 793では2分だが、Linuxと同様にTIMEWAITが60秒になるようにする
+const DEFAULT_MSL: Duration = Duration::from_secs(30);
 const PORT_RANGE: Range<u16> = 40000..60000;
 
-pub struct TCP {
+pub struct TCP<D: Device = PnetDevice> {
     // ハッシュテーブルは複数のスレッドから書き込まれるためRwLockで保護する
     // RwLockは多数のreaderまたは最大1人のwriterを許可する
-    sockets: RwLock<HashMap<SockID, Socket>>,
+    sockets: RwLock<HashMap<SockID, Socket<D>>>,
     // TCPEventをCondVarを通じて送受信する
-    event_condvar: (Mutex<Option<TCPEvent>>, Condvar),
+    // 待機側が取り出すまでイベントを保持しておき、別のイベントによる上書きで通知が失われないようにする
+    event_condvar: (Mutex<Vec<TCPEvent>>, Condvar),
+    // RSTの受信や再送のタイムアウトにより破棄したソケットと、その理由
+    // 破棄されたソケットに対する呼び出しにエラーを返すために、closeされるまで保持する
+    aborted: Mutex<HashMap<SockID, io::ErrorKind>>,
+    // パケットの送受信に利用するバックエンド
+    device: Arc<D>,
+    // 再送タイマーが参照する時計
+    clock: Arc<dyn Clock>,
+    // TIMEWAIT状態のソケットは2MSLの間保持される
+    msl: Mutex<Duration>,
+    // listenやconnectで生成するソケットが利用する輻輳制御アルゴリズム
+    // 受け入れたソケットは、リスニングソケットのアルゴリズムを引き継ぐ
+    default_congestion_control: Mutex<Box<dyn CongestionControl>>,
 }
 
 impl TCP {
+    // カーネルのRAWソケットを利用するTCPインスタンスを生成する
     pub fn new() -> Arc<Self> {
-        let sockets = RwLock::new(HashMap::new());
+        let device = PnetDevice::new().expect("failed to open raw socket");
+        Self::with_device(device)
+    }
+}
+
+impl<D: Device> TCP<D> {
+    // 指定したバックエンドを利用するTCPインスタンスを生成する
+    pub fn with_device(device: D) -> Arc<Self> {
+        Self::with_clock(device, Arc::new(SystemClock))
+    }
+
+    // 指定したバックエンドと時計を利用するTCPインスタンスを生成する
+    // テストではVirtualClockを渡すことで、タイムアウトを実時間を待たずに発生させられる
+    pub fn with_clock(device: D, clock: Arc<dyn Clock>) -> Arc<Self> {
         // Arcを返す
         // Arc/Rcは参照カウントされた共有スマートポインタ
-        let tcp = Arc::new(Self {
-            sockets,
-            event_condvar: (Mutex::new(None), Condvar::new()),
-        });
+        let tcp = Arc::new(Self::without_threads(device, clock));
         let cloned_tcp = tcp.clone();
         std::thread::spawn(move || {
             // パケットの受診用スレッド
@@ -49,12 +89,74 @@ impl TCP {
         tcp
     }
 
+    // 受信スレッドとタイマースレッドを起動せずにTCPインスタンスを生成する
+    // セグメントはdispatchで直接入力する。キャプチャの再生のように、呼び出し元のスレッドだけで完結させる場合に利用する
+    pub(crate) fn without_threads(device: D, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            sockets: RwLock::new(HashMap::new()),
+            event_condvar: (Mutex::new(Vec::new()), Condvar::new()),
+            aborted: Mutex::new(HashMap::new()),
+            device: Arc::new(device),
+            clock,
+            msl: Mutex::new(DEFAULT_MSL),
+            default_congestion_control: Mutex::new(Box::new(NewReno::new())),
+        }
+    }
+
+    // ソケットが利用する輻輳制御アルゴリズムを変更する。輻輳ウィンドウの状態は引き継がれる
+    // リスニングソケットに設定すると、それ以降に受け入れるソケットが同じアルゴリズムを利用する
+    pub fn set_congestion_control<C: CongestionControl + 'static>(
+        &self,
+        sock_id: SockID,
+        algorithm: C,
+    ) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .ok_or_else(|| self.socket_not_found(sock_id))?;
+        dbg!("congestion control", algorithm.name());
+        socket.congestion_control = Box::new(algorithm);
+        Ok(())
+    }
+
+    // これから生成するソケットが利用する輻輳制御アルゴリズムを変更する。既存のソケットには影響しない
+    pub fn set_default_congestion_control<C: CongestionControl + 'static>(&self, algorithm: C) {
+        dbg!("default congestion control", algorithm.name());
+        *self.default_congestion_control.lock().unwrap() = Box::new(algorithm);
+    }
+
+    // ソケットの統計情報を取得する
+    pub fn stats(&self, sock_id: SockID) -> Result<SocketStats> {
+        let table = self.sockets.read().unwrap();
+        let socket = table
+            .get(&sock_id)
+            .ok_or_else(|| self.socket_not_found(sock_id))?;
+        Ok(socket.stats())
+    }
+
+    // 新しいソケットのために、デフォルトの輻輳制御アルゴリズムを初期状態で生成する
+    fn new_congestion_control(&self) -> Box<dyn CongestionControl> {
+        self.default_congestion_control
+            .lock()
+            .unwrap()
+            .new_instance()
+    }
+
+    // MSLを変更する。TIMEWAIT状態のソケットはMSLの2倍の時間が経過した後に破棄される
+    pub fn set_msl(&self, msl: Duration) {
+        *self.msl.lock().unwrap() = msl;
+    }
+
     // タイマースレッド用の関数
     // 全てのソケットの再送キューを見て、タイムアウトしているパケットを再送する
+    // 再送回数が上限に達した場合は接続を破棄する
+    // また、TIMEWAIT状態で2MSLが経過したソケットを破棄する
     fn timer(&self) {
         dbg!("begin timer thread");
         loop {
             let mut table = self.sockets.write().unwrap();
+            // 再送回数の上限に達したソケット。ループの後で破棄する
+            let mut timed_out = Vec::new();
             for (sock_id, socket) in table.iter_mut() {
                 while let Some(mut item) = socket.retransmission_queue.pop_front() {
                     // 再送キューからackされたセグメントを除去する
@@ -62,7 +164,6 @@ impl TCP {
                     if socket.send_param.unacked_seq > item.packet.get_seq() {
                         // ackされてる
                         dbg!("successfully acked", item.packet.get_seq());
-                        socket.send_param.window += item.packet.payload().len() as u16;
                         self.publish_event(*sock_id, TCPEventKind::Acked);
                         if item.packet.get_flag() & tcpflags::FIN > 0
                             && socket.status == TcpStatus::LastAck
@@ -72,9 +173,12 @@ impl TCP {
                         continue;
                     }
                     // タイムアウトを確認
-                    if item.latest_transmission_time.elapsed().unwrap()
-                        < Duration::from_secs(RETRANSMITTION_TIMEOUT)
-                    {
+                    let elapsed = self
+                        .clock
+                        .now()
+                        .duration_since(item.latest_transmission_time)
+                        .unwrap_or_default();
+                    if elapsed < socket.rtt.rto() {
                         // 取り出したエントリがタイムアウトしてないなら，キューの以降のエントリもタイムアウトしてない
                         // 先頭に戻す
                         socket.retransmission_queue.push_front(item);
@@ -84,39 +188,102 @@ impl TCP {
                     if item.transmission_count < MAX_TRANSMITTION {
                         // 再送
                         dbg!("retransmit");
+                        // このセグメントの最初の再送であれば、新しいロスとして扱う
+                        let first_retransmission = item.transmission_count == 1;
                         socket
                             .sender
-                            .send_to(item.packet.clone(), IpAddr::V4(socket.remote_addr))
+                            .send(&item.packet, socket.local_addr, socket.remote_addr)
                             .context("failed to retransmit")
                             .unwrap();
                         item.transmission_count += 1;
-                        item.latest_transmission_time = SystemTime::now();
-                        socket.retransmission_queue.push_back(item);
+                        item.latest_transmission_time = self.clock.now();
+                        item.delivery = socket
+                            .delivery_rate
+                            .on_send(socket.flight_size(), item.latest_transmission_time);
+                        socket.retransmissions += 1;
+                        // 再送のたびにRTOを2倍にする
+                        socket.rtt.backoff();
+                        // タイムアウトした場合は高速リカバリを打ち切り、輻輳制御に通知する
+                        // SYNの再送はまだデータを送信していないので対象外
+                        socket.dup_ack_count = 0;
+                        socket.recovery_point = None;
+                        // 同じセグメントの再送が続く間は、ssthreshやW_maxを下げ直さずに1セグメントからやり直すだけにする
+                        // https://datatracker.ietf.org/doc/html/rfc5681#section-3.1
+                        if item.packet.get_flag() & tcpflags::SYN == 0 {
+                            if first_retransmission {
+                                let flight_size = socket.flight_size();
+                                socket
+                                    .congestion_control
+                                    .on_timeout(&mut socket.congestion_window, flight_size);
+                            } else {
+                                socket.congestion_window.cwnd = socket.congestion_window.mss;
+                            }
+                        }
+                        // 先頭に戻してキューをシーケンス番号順に保つ
+                        // 高速再送(retransmit_head)は先頭が最も古い未確認のセグメントであることを前提にしている
+                        socket.retransmission_queue.push_front(item);
                         break;
                     } else {
+                        // 相手に届かないので接続を維持できない
                         dbg!("reached MAX_TRANSMITTION");
-                        if item.packet.get_flag() & tcpflags::FIN > 0
-                            && (socket.status == TcpStatus::LastAck
-                                || socket.status == TcpStatus::FinWait1
-                                || socket.status == TcpStatus::FinWait2)
-                        {
-                            self.publish_event(*sock_id, TCPEventKind::ConnectionClosed)
-                        }
+                        timed_out.push(*sock_id);
+                        break;
                     }
                 }
             }
+            for sock_id in timed_out {
+                let socket = &table[&sock_id];
+                if socket.status == TcpStatus::SynRcvd && socket.listening_socket.is_some() {
+                    // acceptされる前なので、ソケットを破棄するだけでよい
+                    table.remove(&sock_id);
+                    self.discard_events(sock_id);
+                } else {
+                    // connect、send、recv、closeで待機しているスレッドにタイムアウトを返させる
+                    self.abort(&mut table, sock_id, io::ErrorKind::TimedOut);
+                }
+            }
+            let time_wait = *self.msl.lock().unwrap() * 2;
+            let now = self.clock.now();
+            table.retain(|sock_id, socket| {
+                let expired = socket.time_wait_started.is_some_and(|started| {
+                    now.duration_since(started).unwrap_or_default() >= time_wait
+                });
+                if expired {
+                    dbg!("status: timewait -> closed", sock_id);
+                    self.discard_events(*sock_id);
+                }
+                !expired
+            });
             // ロックを外して待機する
             drop(table);
-            thread::sleep(Duration::from_millis(100));
+            self.clock.sleep(CLOCK_GRANULARITY);
         }
     }
 
     // 接続を閉じる．
+    // ESTABLISHEDからはFINWAIT1へ、相手がFINを送信済みのCLOSEWAITからはLASTACKへ遷移し、
+    // 送信したFINに対する処理が完了するまでブロックする
+    // RSTやタイムアウトにより既に破棄されていた場合はその理由をエラーとして返す
     pub fn close(&self, sock_id: SockID) -> Result<()> {
         let mut table = self.sockets.write().unwrap();
-        let mut socket = table
-            .get_mut(&sock_id)
-            .context(format!("no such socket: {:?}", sock_id))?;
+        let socket = match table.get_mut(&sock_id) {
+            Some(socket) => socket,
+            None => {
+                let error = self.socket_not_found(sock_id);
+                self.aborted.lock().unwrap().remove(&sock_id);
+                return Err(error);
+            }
+        };
+        let next_status = match socket.status {
+            TcpStatus::Established => TcpStatus::FinWait1,
+            TcpStatus::CloseWait => TcpStatus::LastAck,
+            TcpStatus::Listen => {
+                table.remove(&sock_id);
+                self.discard_events(sock_id);
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
         socket.send_tcp_packet(
             socket.send_param.next,
             socket.recv_param.next,
@@ -124,74 +291,201 @@ impl TCP {
             &[],
         )?;
         socket.send_param.next += 1;
-        match socket.status {
-            TcpStatus::Established => {
-                socket.status = TcpStatus::FinWait1;
-                // ロックを外してイベントの待機。
-                // 受信スレッドがロックを取得できるようにするため。
-                drop(table);
-                self.wait_event(sock_id, TCPEventKind::ConnectionClosed);
-                let mut table = self.sockets.write().unwrap();
-                table.remove(&sock_id);
-                dbg!("closed & removed", sock_id);
-            }
-            TcpStatus::CloseWait => {
-                socket.status = TcpStatus::LastAck;
-                // ロックを外してイベントの待機。
-                // 受信スレッドがロックを取得できるようにするため．
-                drop(table);
-                self.wait_event(sock_id, TCPEventKind::ConnectionClosed);
-                let mut table = self.sockets.write().unwrap();
-                table.remove(&sock_id);
-                dbg!("closed & removed", sock_id);
-            }
-            TcpStatus::Listen => {
-                table.remove(&sock_id);
-            }
-            _ => return Ok(()),
+        socket.status = next_status;
+        dbg!("status: -> ", &socket.status);
+        // ロックを外してイベントの待機。
+        // 受信スレッドがロックを取得できるようにするため。
+        drop(table);
+        let result = self.wait_event(sock_id, TCPEventKind::ConnectionClosed);
+        let mut table = self.sockets.write().unwrap();
+        // TIMEWAIT状態のソケットは、遅れて届くセグメントに応答するため2MSLの間残しておく
+        if table
+            .get(&sock_id)
+            .is_some_and(|socket| socket.status == TcpStatus::TimeWait)
+        {
+            // recvで待機しているスレッドがFINによるイベントをまだ受け取っていないことがあるので、
+            // イベントは2MSLが経過してソケットを破棄する時に捨てる
+            dbg!("closed", sock_id);
+        } else {
+            table.remove(&sock_id);
+            self.discard_events(sock_id);
+            dbg!("closed & removed", sock_id);
         }
-        Ok(())
+        self.aborted.lock().unwrap().remove(&sock_id);
+        result
     }
 
     /// 指定したソケットIDと種別のイベントを待機
-    fn wait_event(&self, sock_id: SockID, kind: TCPEventKind) {
+    /// 待機中にソケットがRSTやタイムアウトにより破棄された場合はエラーを返す
+    fn wait_event(&self, sock_id: SockID, kind: TCPEventKind) -> Result<()> {
         let (lock, cvar) = &self.event_condvar;
-        let mut event = lock.lock().unwrap();
+        let mut events = lock.lock().unwrap();
         loop {
-            if let Some(ref e) = *event {
-                if e.sock_id == sock_id && e.kind == kind {
-                    break;
-                }
+            if let Some(i) = events
+                .iter()
+                .position(|e| e.sock_id == sock_id && e.kind == kind)
+            {
+                let event = events.swap_remove(i);
+                dbg!(&event);
+                return Ok(());
             }
-            // cvarがnotifyされるまでeventのロックを外して待機
-            event = cvar.wait(event).unwrap();
+            if let Some(kind) = self.aborted.lock().unwrap().get(&sock_id) {
+                return Err(io::Error::from(*kind).into());
+            }
+            // cvarがnotifyされるまでeventsのロックを外して待機
+            events = cvar.wait(events).unwrap();
         }
-        dbg!(&event);
-        *event = None;
     }
 
     /// 指定のソケットIDにイベントを発行する
     fn publish_event(&self, sock_id: SockID, kind: TCPEventKind) {
         let (lock, cvar) = &self.event_condvar;
-        let mut e = lock.lock().unwrap();
-        *e = Some(TCPEvent::new(sock_id, kind));
+        let mut events = lock.lock().unwrap();
+        let event = TCPEvent::new(sock_id, kind);
+        // 同じイベントが未処理のまま残っている場合はまとめる
+        if !events.contains(&event) {
+            events.push(event);
+        }
+        cvar.notify_all();
+    }
+
+    /// 削除したソケットに対する未処理のイベントを破棄する
+    fn discard_events(&self, sock_id: SockID) {
+        let (lock, _) = &self.event_condvar;
+        lock.lock().unwrap().retain(|e| e.sock_id != sock_id);
+    }
+
+    /// ソケットを破棄し、そのソケットで待機しているスレッドにエラーを返させる
+    fn abort(&self, table: &mut HashMap<SockID, Socket<D>>, sock_id: SockID, kind: io::ErrorKind) {
+        // 再送キューもソケットと一緒に破棄される
+        table.remove(&sock_id);
+        self.aborted.lock().unwrap().insert(sock_id, kind);
+        let (lock, cvar) = &self.event_condvar;
+        lock.lock().unwrap().retain(|e| e.sock_id != sock_id);
         cvar.notify_all();
+        dbg!("aborted", sock_id, kind);
+    }
+
+    /// 指定したソケットが存在しない場合のエラー。RSTやタイムアウトにより破棄されていればその理由を返す
+    fn socket_not_found(&self, sock_id: SockID) -> anyhow::Error {
+        match self.aborted.lock().unwrap().get(&sock_id) {
+            Some(kind) => io::Error::from(*kind).into(),
+            None => anyhow::anyhow!("no such socket: {:?}", sock_id),
+        }
+    }
+
+    // 受信したセグメントに対してRSTを送信する
+    // https://datatracker.ietf.org/doc/html/rfc793#section-3.4 (Reset Generation)
+    fn send_reset(
+        &self,
+        packet: &TCPPacket,
+        local_addr: IpAddr,
+        remote_addr: IpAddr,
+    ) -> Result<()> {
+        if packet.get_flag() & tcpflags::RST > 0 {
+            // RSTに対してRSTは返さない
+            return Ok(());
+        }
+        let builder = TCPPacketBuilder::new()
+            .src(packet.get_dest())
+            .dest(packet.get_src());
+        let builder = if packet.get_flag() & tcpflags::ACK > 0 {
+            // 相手が次に期待しているシーケンス番号を使う
+            builder.seq(packet.get_ack()).flag(tcpflags::RST)
+        } else {
+            // SYNとFINもシーケンス番号を1つ消費する
+            let mut segment_len = packet.payload().len() as u32;
+            if packet.get_flag() & tcpflags::SYN > 0 {
+                segment_len += 1;
+            }
+            if packet.get_flag() & tcpflags::FIN > 0 {
+                segment_len += 1;
+            }
+            builder
+                .ack(packet.get_seq() + segment_len)
+                .flag(tcpflags::RST | tcpflags::ACK)
+        };
+        let reset = builder.build(local_addr, remote_addr)?;
+        self.device
+            .send(&reset, local_addr, remote_addr)
+            .context("failed to send reset")?;
+        dbg!("sent reset", &reset);
+        Ok(())
+    }
+
+    // RSTビットが立ったセグメントの処理
+    // https://datatracker.ietf.org/doc/html/rfc793#page-37 (Reset Processing)
+    fn reset_handler(
+        &self,
+        mut table: RwLockWriteGuard<HashMap<SockID, Socket<D>>>,
+        sock_id: SockID,
+        packet: &TCPPacket,
+    ) {
+        dbg!("reset handler");
+        let socket = table.get_mut(&sock_id).unwrap();
+        // 第三者が偽造したRSTで接続が切断されないよう、正当なRSTか確認する
+        let acceptable = match socket.status {
+            // SYNSENTでは送信したSYNに対するACKを持つRSTだけを受け付ける
+            TcpStatus::SynSent => {
+                packet.get_flag() & tcpflags::ACK > 0
+                    && socket.send_param.unacked_seq < packet.get_ack()
+                    && packet.get_ack() <= socket.send_param.next
+            }
+            // それ以外ではシーケンス番号が受信ウィンドウ内にあるRSTだけを受け付ける
+            _ => {
+                let next = socket.recv_param.next;
+                packet.get_seq() == next
+                    || packet
+                        .get_seq()
+                        .in_range(next, next + socket.recv_param.window as u32)
+            }
+        };
+        if !acceptable {
+            dbg!("unacceptable reset");
+            return;
+        }
+        dbg!("status: -> closed", &socket.status);
+        match socket.status {
+            TcpStatus::SynSent => self.abort(&mut table, sock_id, io::ErrorKind::ConnectionRefused),
+            TcpStatus::SynRcvd if socket.listening_socket.is_some() => {
+                // パッシブオープンの途中であればacceptされる前なので、ソケットを破棄するだけでよい
+                // リスニングソケットは引き続きLISTEN状態にある
+                table.remove(&sock_id);
+                self.discard_events(sock_id);
+            }
+            TcpStatus::SynRcvd => self.abort(&mut table, sock_id, io::ErrorKind::ConnectionRefused),
+            TcpStatus::LastAck | TcpStatus::Closing => {
+                // 送信したFINへのACKの代わりにRSTが届いた。接続は閉じたものとして扱う
+                table.remove(&sock_id);
+                self.publish_event(sock_id, TCPEventKind::ConnectionClosed);
+            }
+            TcpStatus::TimeWait => {
+                // 既にcloseされているので、通知せずにソケットを破棄する
+                table.remove(&sock_id);
+                self.discard_events(sock_id);
+            }
+            _ => self.abort(&mut table, sock_id, io::ErrorKind::ConnectionReset),
+        }
     }
 
     // LISTEN状態のソケットに到着したパケットの処理
     fn listen_handler(
         &self,
-        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
+        mut table: RwLockWriteGuard<HashMap<SockID, Socket<D>>>,
         listening_socket_id: SockID,
         packet: &TCPPacket,
-        remote_addr: Ipv4Addr,
+        remote_addr: IpAddr,
     ) -> Result<()> {
         dbg!("listen handler");
-        if packet.get_flag() & tcpflags::ACK > 0 {
-            // 本来ならRSTをsendする
+        if packet.get_flag() & tcpflags::RST > 0 {
+            // 接続がないのでRSTは無視する
             return Ok(());
         }
         let listening_socket = table.get_mut(&listening_socket_id).unwrap();
+        if packet.get_flag() & tcpflags::ACK > 0 {
+            // まだ何も送信していないので、どのACKも受け入れられない
+            return self.send_reset(packet, listening_socket.local_addr, remote_addr);
+        }
         if packet.get_flag() & tcpflags::SYN > 0 {
             // passive openの処理
             // 後に接続済みソケットとなるソケットを新たに生成する
@@ -201,10 +495,15 @@ impl TCP {
                 listening_socket.local_port,
                 packet.get_src(),
                 TcpStatus::SynRcvd,
+                self.device.clone(),
+                self.clock.clone(),
             )?;
+            connection_socket.congestion_control =
+                listening_socket.congestion_control.new_instance();
+            connection_socket.set_send_mss(packet);
             connection_socket.recv_param.next = packet.get_seq() + 1;
             connection_socket.recv_param.initial_seq = packet.get_seq();
-            connection_socket.send_param.initial_seq = rand::thread_rng().gen_range(1..1 << 31);
+            connection_socket.send_param.initial_seq = SeqNum::new(rand::thread_rng().gen());
             connection_socket.send_param.window = packet.get_window_size();
             connection_socket.send_tcp_packet(
                 connection_socket.send_param.initial_seq,
@@ -216,6 +515,11 @@ impl TCP {
             connection_socket.send_param.unacked_seq = connection_socket.send_param.initial_seq;
             connection_socket.listening_socket = Some(listening_socket.get_sock_id());
             dbg!("status: listen -> ", &connection_socket.status);
+            // 同じソケットIDで以前に破棄された接続の記録は新しい接続には関係ない
+            self.aborted
+                .lock()
+                .unwrap()
+                .remove(&connection_socket.get_sock_id());
             table.insert(connection_socket.get_sock_id(), connection_socket);
         }
         Ok(())
@@ -224,68 +528,92 @@ impl TCP {
     /// SYNRCVD状態のソケットに到着したパケットの処理
     fn synrcvd_handler(
         &self,
-        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
+        mut table: RwLockWriteGuard<HashMap<SockID, Socket<D>>>,
         sock_id: SockID,
         packet: &TCPPacket,
     ) -> Result<()> {
         dbg!("synrcvd handler");
         let socket = table.get_mut(&sock_id).unwrap();
 
+        // SND.UNA < SEG.ACK <= SND.NXT のACKだけが送信したSYNを確認している
         if packet.get_flag() & tcpflags::ACK > 0
-            && socket.send_param.unacked_seq <= packet.get_ack()
+            && socket.send_param.unacked_seq < packet.get_ack()
             && packet.get_ack() <= socket.send_param.next
         {
-            socket.recv_param.next = packet.get_seq();
+            // RCV.NXTは相手のSYNを受信した時点で設定済み
             socket.send_param.unacked_seq = packet.get_ack();
             socket.status = TcpStatus::Established;
             dbg!("status: synrcvd ->", &socket.status);
-            if let Some(id) = socket.listening_socket {
-                let ls = table.get_mut(&id).unwrap();
-                ls.connected_connection_queue.push_back(sock_id);
-                self.publish_event(ls.get_sock_id(), TCPEventKind::ConnectionCompleted);
+            if packet.get_flag() & tcpflags::SYN > 0 {
+                // 同時オープンでは相手もSYNRCVDにあり、SYN|ACKへのACKを待っている
+                socket.send_tcp_packet(
+                    socket.send_param.next,
+                    socket.recv_param.next,
+                    tcpflags::ACK,
+                    &[],
+                )?;
+            }
+            match socket.listening_socket {
+                Some(id) => {
+                    let ls = table.get_mut(&id).unwrap();
+                    ls.connected_connection_queue.push_back(sock_id);
+                    self.publish_event(ls.get_sock_id(), TCPEventKind::ConnectionCompleted);
+                }
+                // 同時オープンの場合はconnectを呼んだスレッドが待機している
+                None => self.publish_event(sock_id, TCPEventKind::ConnectionCompleted),
             }
+        } else if packet.get_flag() & tcpflags::ACK > 0 {
+            // 送信していないセグメントに対するACKにはRSTを返す。状態は変えない
+            return self.send_reset(packet, socket.local_addr, socket.remote_addr);
         }
         Ok(())
     }
 
     // SYNSENT状態のソケットに到着したパケットの処理
     // SYNを送信した後なので、相手からSYN|ACKセグメントを受け取ればコネクションが確立され、アクティブオープン成功になる
-    fn synsent_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+    // ACKのないSYNを受け取った場合は、相手も同時にアクティブオープンを行っている(同時オープン)
+    // https://datatracker.ietf.org/doc/html/rfc793#page-32 (Simultaneous Connection Synchronization)
+    fn synsent_handler(&self, socket: &mut Socket<D>, packet: &TCPPacket) -> Result<()> {
         dbg!("synsent handler");
-        // ACKビットが立っている
+        // ACKビットが立っている場合、セグメントの確認応答番号は送信したSYNを確認するものである必要がある
+        // そうでなければ以前の接続のセグメントなので、RSTを返して破棄する。状態は変えない
         if packet.get_flag() & tcpflags::ACK > 0
-            // セグメントの確認応答番号は正しい範囲内に含まれる必要がある
-            && socket.send_param.unacked_seq <= packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-            // SYNビットが立っている
-            && packet.get_flag() & tcpflags::SYN > 0
+            && !(socket.send_param.unacked_seq < packet.get_ack()
+                && packet.get_ack() <= socket.send_param.next)
         {
-            socket.recv_param.next = packet.get_seq() + 1;
-            socket.recv_param.initial_seq = packet.get_seq();
+            return self.send_reset(packet, socket.local_addr, socket.remote_addr);
+        }
+        // SYNビットが立っていなければ何もしない
+        if packet.get_flag() & tcpflags::SYN == 0 {
+            return Ok(());
+        }
+        socket.set_send_mss(packet);
+        socket.recv_param.next = packet.get_seq() + 1;
+        socket.recv_param.initial_seq = packet.get_seq();
+        socket.send_param.window = packet.get_window_size();
+        if packet.get_flag() & tcpflags::ACK > 0 {
             socket.send_param.unacked_seq = packet.get_ack();
-            socket.send_param.window = packet.get_window_size();
-            if socket.send_param.unacked_seq > socket.send_param.initial_seq {
-                socket.status = TcpStatus::Established;
-                socket.send_tcp_packet(
-                    socket.send_param.next,
-                    socket.recv_param.next,
-                    tcpflags::ACK,
-                    &[],
-                )?;
-                dbg!("status: synsend ->", &socket.status);
-                self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionCompleted);
-            } else {
-                // SYNSENTの状態でSYNを受け取ったらSYNRCVDに遷移する
-                // 一つ上のifでACKが立っていることを条件にしているのでSYNを受け取らないような？
-                socket.status = TcpStatus::SynRcvd;
-                socket.send_tcp_packet(
-                    socket.send_param.next,
-                    socket.recv_param.next,
-                    tcpflags::ACK,
-                    &[],
-                )?;
-                dbg!("status: synsent ->", &socket.status);
-            }
+            socket.status = TcpStatus::Established;
+            socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
+            )?;
+            dbg!("status: synsend ->", &socket.status);
+            self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionCompleted);
+        } else {
+            // SYNSENTの状態でSYNを受け取ったらSYNRCVDに遷移し、同じ初期シーケンス番号でSYN|ACKを送る
+            // 以降はSYNではなくSYN|ACKを再送するので、再送キューのSYNは取り除く
+            socket.status = TcpStatus::SynRcvd;
+            socket.retransmission_queue.clear();
+            socket.send_tcp_packet(
+                socket.send_param.initial_seq,
+                socket.recv_param.next,
+                tcpflags::SYN | tcpflags::ACK,
+                &[],
+            )?;
+            dbg!("status: synsent ->", &socket.status);
         }
         Ok(())
     }
@@ -293,156 +621,204 @@ impl TCP {
     // 受信スレッド用のメソッド
     fn receive_handler(&self) -> Result<()> {
         dbg!("begin recv thread");
-        let (_, mut receiver) = transport::transport_channel(
-            65535,
-            // IPアドレスが必要なのでIPパケットレベルで取得
-            TransportChannelType::Layer3(IpNextHeaderProtocols::Tcp),
-        ).unwrap();
-        let mut packet_iter = transport::ipv4_packet_iter(&mut receiver);
         loop {
             // パケットを受信するまでスレッドをブロックして待機する
-            let (packet, remote_addr) = match packet_iter.next() {
-                Ok((p, r)) => (p, r),
-                Err(_) => continue,
-            };
-            let local_addr = packet.get_destination();
-            // pnetのTcpPacketを生成
-            let tcp_packet = match TcpPacket::new(packet.payload()) {
-                Some(p) => p,
-                None => {
-                    continue;
-                }
-            };
-            // pnetのTcpPacketからtcp::TCPPacketに変換する
-            let packet = TCPPacket::from(tcp_packet);
-            let remote_addr = match remote_addr {
-                IpAddr::V4(addr) => addr,
-                _ => { continue; }
-            };
-            // RwLockからwriteでロックを取得し、中身(HashMap)を取り出す
-            let mut table = self.sockets.write().unwrap();
-            // ヘッダの情報から対応するソケットを取り出す
-            // mapの値を取得するときにはキーの型の借用にしないといけない
-            // 取得した値を変更するので、getでなくget_mutを使う
-            let socket = match table.get_mut(&SockID(
+            let (packet, remote_addr, local_addr) = self.device.recv()?;
+            self.dispatch(&packet, remote_addr, local_addr);
+        }
+    }
+
+    // 受信したセグメントを対応するソケットの状態に応じたハンドラへ振り分ける
+    // 受信スレッドの他に、キャプチャを再生する場合にも利用する
+    pub(crate) fn dispatch(
+        &self,
+        packet: &TCPPacket,
+        remote_addr: IpAddr,
+        local_addr: IpAddr,
+    ) {
+        if !packet.is_valid_data_offset() {
+            dbg!("invalid data offset", packet.get_data_offset());
+            return;
+        }
+        // RwLockからwriteでロックを取得し、中身(HashMap)を取り出す
+        let mut table = self.sockets.write().unwrap();
+        // ヘッダの情報から対応するソケットを取り出す
+        // mapの値を取得するときにはキーの型の借用にしないといけない
+        // 取得した値を変更するので、getでなくget_mutを使う
+        let socket = match table.get_mut(&SockID(
+            local_addr,
+            remote_addr,
+            packet.get_dest(),
+            packet.get_src(),
+        )) {
+            Some(socket) => socket, // 接続済みソケット
+            None => match table.get_mut(&SockID(
                 local_addr,
-                remote_addr,
+                undetermined_addr(local_addr),
                 packet.get_dest(),
-                packet.get_src(),
+                UNDETERMINED_PORT,
             )) {
-                Some(socket) => socket, // 接続済みソケット
-                None => match table.get_mut(&SockID(
-                    local_addr,
-                    UNDETERMINED_IP_ADDR,
-                    packet.get_dest(),
-                    UNDETERMINED_PORT,
-                )) {
-                    Some(socket) => socket, // リスニングソケット
-                    None => continue, // どのソケットにも該当しないものは無視
-                },
-            };
-            if !packet.is_correct_checksum(local_addr, remote_addr) {
-                dbg!("invalid checksum");
-                continue;
-            }
-            let sock_id = socket.get_sock_id();
-            // ソケットの状態から対応するハンドラを呼び出す
-            if let Err(error) = match socket.status {
-                TcpStatus::Listen => self.listen_handler(table, sock_id, &packet, remote_addr),
-                TcpStatus::SynRcvd => self.synrcvd_handler(table, sock_id, &packet),
-                TcpStatus::SynSent => self.synsent_handler(socket, &packet),
-                TcpStatus::Established => self.established_handler(socket, &packet),
-                TcpStatus::CloseWait | TcpStatus::LastAck => self.close_handler(socket, &packet),
-                TcpStatus::FinWait1 | TcpStatus::FinWait2 => self.finwait_handler(socket, &packet),
-                _ => {
-                    dbg!("not implemented state");
-                    Ok(())
+                Some(socket) => socket, // リスニングソケット
+                None => {
+                    // どのソケットにも該当しないものにはRSTを返す
+                    if self.device.resets_unmatched()
+                        && packet.is_correct_checksum(local_addr, remote_addr)
+                    {
+                        if let Err(error) = self.send_reset(packet, local_addr, remote_addr) {
+                            dbg!(error);
+                        }
+                    }
+                    return;
                 }
-            } {
-                dbg!(error);
-            }
+            },
+        };
+        if !packet.is_correct_checksum(local_addr, remote_addr) {
+            dbg!("invalid checksum");
+            return;
+        }
+        let sock_id = socket.get_sock_id();
+        if packet.get_flag() & tcpflags::RST > 0 && socket.status != TcpStatus::Listen {
+            self.reset_handler(table, sock_id, packet);
+            return;
+        }
+        // ソケットの状態から対応するハンドラを呼び出す
+        if let Err(error) = match socket.status {
+            TcpStatus::Listen => self.listen_handler(table, sock_id, packet, remote_addr),
+            TcpStatus::SynRcvd => self.synrcvd_handler(table, sock_id, packet),
+            TcpStatus::SynSent => self.synsent_handler(socket, packet),
+            TcpStatus::Established => self.established_handler(socket, packet),
+            TcpStatus::CloseWait | TcpStatus::LastAck => self.close_handler(socket, packet),
+            TcpStatus::FinWait1 | TcpStatus::FinWait2 => self.finwait_handler(socket, packet),
+            TcpStatus::Closing => self.closing_handler(socket, packet),
+            TcpStatus::TimeWait => self.timewait_handler(socket, packet),
+        } {
+            dbg!(error);
         }
-
     }
 
     // ESTABLISHED状態のソケットに到着したパケットの処理
-    fn established_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+    fn established_handler(&self, socket: &mut Socket<D>, packet: &TCPPacket) -> Result<()> {
         dbg!("established handler");
-        if socket.send_param.unacked_seq < packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-        {
-            socket.send_param.unacked_seq = packet.get_ack();
-            self.delete_acked_segment_from_retransmission_queue(socket);
-        } else if socket.send_param.next < packet.get_ack() {
+        if socket.send_param.next < packet.get_ack() {
             // 未送信セグメントに対するackは破棄
             return Ok(());
         }
+        self.process_ack(socket, packet)?;
         if packet.get_flag() & tcpflags::ACK == 0 {
             // ACKが立っていないパケットは破棄
             return Ok(());
         }
         if !packet.payload().is_empty() {
-            self.process_payload(socket, &packet)?;
-        }
-        Ok(())
-    }
-
-    // パケットのペイロードを受信バッファにコピーする
-    fn process_payload(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        // バッファにおける読み込みのヘッド位置．
-        let offset = socket.recv_buffer.len() - socket.recv_param.window as usize
-            + (packet.get_seq() - socket.recv_param.next) as usize;
-        let copy_size = cmp::min(packet.payload().len(), socket.recv_buffer.len() - offset);
-        socket.recv_buffer[offset..offset + copy_size]
-            .copy_from_slice(&packet.payload()[..copy_size]);
-        socket.recv_param.tail =
-            cmp::max(socket.recv_param.tail, packet.get_seq() + copy_size as u32); // ロス再送の際穴埋めされるためにmaxをとる
-
-        if packet.get_seq() == socket.recv_param.next {
-            // 順序入れ替わり無しの場合のみrecv_param.nextを進められる
-            socket.recv_param.next = socket.recv_param.tail;
-            socket.recv_param.window -= (socket.recv_param.tail - packet.get_seq()) as u16;
+            self.process_payload(socket, packet)?;
         }
-        if copy_size > 0 {
-            // 受信バッファにコピーが成功
+        // FINはそれまでのデータを全て受信している場合にのみ受け付ける
+        // 取りこぼしたデータがあれば、相手がFINを再送するのを待つ
+        if packet.get_flag() & tcpflags::FIN > 0
+            && packet.get_seq() + packet.payload().len() as u32 == socket.recv_param.next
+        {
+            // パッシブクローズ
+            socket.recv_param.next += 1;
             socket.send_tcp_packet(
                 socket.send_param.next,
                 socket.recv_param.next,
                 tcpflags::ACK,
                 &[],
             )?;
+            socket.status = TcpStatus::CloseWait;
+            dbg!("status: established ->", &socket.status);
+            // recvで待機しているスレッドを起こし、受信バッファが空になったら0を返させる
+            self.publish_event(socket.get_sock_id(), TCPEventKind::DataArrived);
+        }
+        Ok(())
+    }
+
+    // パケットのペイロードを受信バッファにコピーする
+    // 順序が入れ替わって届いたデータも受信ウィンドウ内であればバッファの該当位置に格納しておき、
+    // 穴が埋まった時点でRCV.NXTを連続して受信できた末尾まで進める
+    fn process_payload(&self, socket: &mut Socket<D>, packet: &TCPPacket) -> Result<()> {
+        let mut seq = packet.get_seq();
+        let mut payload = packet.payload();
+        // RCV.NXTより前の部分は受信済みなので取り除く
+        if seq < socket.recv_param.next {
+            let duplicated = cmp::min((socket.recv_param.next - seq) as usize, payload.len());
+            payload = &payload[duplicated..];
+            seq = socket.recv_param.next;
+        }
+        // バッファにおける書き込み位置．未読のデータの後ろから、RCV.NXTとの差だけ離れた位置になる
+        let window = socket.recv_param.window as usize;
+        let offset = (seq - socket.recv_param.next) as usize;
+        let copy_size = cmp::min(payload.len(), window.saturating_sub(offset));
+        if copy_size > 0 {
+            let head = socket.recv_buffer.len() - window + offset;
+            socket.recv_buffer[head..head + copy_size].copy_from_slice(&payload[..copy_size]);
+            socket.reassembly_queue.insert(seq, seq + copy_size as u32);
+            let next = socket
+                .reassembly_queue
+                .pop_contiguous(socket.recv_param.next);
+            let advanced = next - socket.recv_param.next;
+            socket.recv_param.next = next;
+            socket.recv_param.window -= advanced as u16;
+            if advanced > 0 {
+                self.publish_event(socket.get_sock_id(), TCPEventKind::DataArrived);
+            }
+        } else if payload.is_empty() {
+            // 再送などで全て受信済みのセグメント
+            dbg!("duplicate segment");
         } else {
             // 受信バッファが溢れた時はセグメントを破棄
             dbg!("recv buffer overflow");
         }
-        self.publish_event(socket.get_sock_id(), TCPEventKind::DataArrived);
+        // 累積確認応答。順序が入れ替わった場合や重複した場合も、期待しているseqを相手に伝える
+        socket.send_tcp_packet(
+            socket.send_param.next,
+            socket.recv_param.next,
+            tcpflags::ACK,
+            &[],
+        )?;
         Ok(())
     }
 
-    fn close_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+    // CLOSEWAIT or LASTACK状態のソケットに到着したパケットの処理
+    fn close_handler(&self, socket: &mut Socket<D>, packet: &TCPPacket) -> Result<()> {
         dbg!("closewait | lastack handler");
-        socket.send_param.unacked_seq = packet.get_ack();
+        if packet.get_flag() & tcpflags::ACK == 0 {
+            // ACKが立っていないパケットは破棄
+            return Ok(());
+        }
+        self.process_ack(socket, packet)?;
+        if packet.get_flag() & tcpflags::FIN > 0 {
+            // FINに対するACKが失われて相手がFINを再送してきた場合は、もう一度ACKを返す
+            socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
+            )?;
+        }
+        if socket.status == TcpStatus::LastAck
+            && socket.send_param.unacked_seq == socket.send_param.next
+        {
+            // 送信したFINがackされたので接続を閉じる
+            dbg!("status: lastack -> closed");
+            self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionClosed);
+        }
         Ok(())
     }
 
     // FINWAIT1 or FINWAIT2状態のソケットに到着したパケットの処理
-    fn finwait_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+    fn finwait_handler(&self, socket: &mut Socket<D>, packet: &TCPPacket) -> Result<()> {
         dbg!("finwait handler");
-        if socket.send_param.unacked_seq < packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-        {
-            socket.send_param.unacked_seq = packet.get_ack();
-            self.delete_acked_segment_from_retransmission_queue(socket);
-        } else if socket.send_param.next < packet.get_ack() {
+        if socket.send_param.next < packet.get_ack() {
             // 未送信セグメントに対するackは破棄
             return Ok(());
         }
+        self.process_ack(socket, packet)?;
         if packet.get_flag() & tcpflags::ACK == 0 {
             // ACKが立っていないパケットは破棄
             return Ok(());
         }
         if !packet.payload().is_empty() {
-            self.process_payload(socket, &packet)?;
+            self.process_payload(socket, packet)?;
         }
 
         if socket.status == TcpStatus::FinWait1
@@ -453,8 +829,9 @@ impl TCP {
             dbg!("status: finwait1 ->", &socket.status);
         }
 
-        if packet.get_flag() & tcpflags::FIN > 0 {
-            // 本来はCLOSING stateも考慮する必要があるが省略
+        if packet.get_flag() & tcpflags::FIN > 0
+            && packet.get_seq() + packet.payload().len() as u32 == socket.recv_param.next
+        {
             socket.recv_param.next += 1;
             socket.send_tcp_packet(
                 socket.send_param.next,
@@ -462,36 +839,234 @@ impl TCP {
                 tcpflags::ACK,
                 &[],
             )?;
+            if socket.status == TcpStatus::FinWait2 {
+                self.enter_time_wait(socket);
+                self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionClosed);
+            } else {
+                // 送信したFINがまだackされていない。相手も同時にクローズしている
+                socket.status = TcpStatus::Closing;
+                dbg!("status: finwait1 ->", &socket.status);
+            }
+            // recvで待機しているスレッドを起こし、0を返させる
+            self.publish_event(socket.get_sock_id(), TCPEventKind::DataArrived);
+        }
+        Ok(())
+    }
+
+    // CLOSING状態のソケットに到着したパケットの処理
+    // 相手のFINは受信済みで、送信したFINに対するACKを待っている
+    fn closing_handler(&self, socket: &mut Socket<D>, packet: &TCPPacket) -> Result<()> {
+        dbg!("closing handler");
+        if packet.get_flag() & tcpflags::ACK == 0 {
+            // ACKが立っていないパケットは破棄
+            return Ok(());
+        }
+        self.process_ack(socket, packet)?;
+        if packet.get_flag() & tcpflags::FIN > 0 {
+            // 送信したACKが失われて相手がFINを再送してきた
+            socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
+            )?;
+        }
+        if socket.send_param.unacked_seq == socket.send_param.next {
+            // 送信したFINがackされた
+            self.enter_time_wait(socket);
             self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionClosed);
         }
         Ok(())
     }
 
-    fn delete_acked_segment_from_retransmission_queue(&self, socket: &mut Socket) {
+    // TIMEWAIT状態のソケットに到着したパケットの処理
+    // 送信したACKが失われると相手はFINを再送してくるので、改めてACKを返す
+    fn timewait_handler(&self, socket: &mut Socket<D>, packet: &TCPPacket) -> Result<()> {
+        dbg!("timewait handler");
+        if packet.get_flag() & tcpflags::FIN > 0 {
+            socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
+            )?;
+            // 2MSLのタイマーを再始動する
+            self.enter_time_wait(socket);
+        }
+        Ok(())
+    }
+
+    // TIMEWAIT状態に遷移し、2MSLのタイマーを開始する
+    fn enter_time_wait(&self, socket: &mut Socket<D>) {
+        socket.status = TcpStatus::TimeWait;
+        socket.time_wait_started = Some(self.clock.now());
+        dbg!("status: -> ", &socket.status);
+    }
+
+    // ACKの処理
+    // SND.UNAと相手の受信ウィンドウを更新し、ACKされたセグメントを再送キューから除去する
+    fn process_ack(&self, socket: &mut Socket<D>, packet: &TCPPacket) -> Result<()> {
+        if packet.get_flag() & tcpflags::ACK == 0 {
+            return Ok(());
+        }
+        let ack = packet.get_ack();
+        if socket.send_param.unacked_seq < ack && ack <= socket.send_param.next {
+            let acked = (ack - socket.send_param.unacked_seq) as usize;
+            socket.send_param.unacked_seq = ack;
+            socket.send_param.window = packet.get_window_size();
+            self.delete_acked_segment_from_retransmission_queue(socket, acked)?;
+        } else if ack == socket.send_param.unacked_seq {
+            if packet.get_window_size() != socket.send_param.window {
+                // ウィンドウの更新。相手が受信バッファから読み出して空きができた
+                socket.send_param.window = packet.get_window_size();
+                self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
+            } else {
+                self.duplicate_ack_handler(socket, packet)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_acked_segment_from_retransmission_queue(
+        &self,
+        socket: &mut Socket<D>,
+        acked: usize,
+    ) -> Result<()> {
         dbg!("ack accept", socket.send_param.unacked_seq);
+        // 今回のACKで確認された中で最後に送信したセグメント。RTTの測定に使う
+        let mut latest_acked = None;
+        // 今回のACKで確認された中で最も新しい配送状況。配送レートの測定に使う
+        let mut newest_delivery: Option<DeliveryState> = None;
         while let Some(item) = socket.retransmission_queue.pop_front() {
             if socket.send_param.unacked_seq > item.packet.get_seq() {
                 // ackされてるので除去
                 dbg!("successfully acked", item.packet.get_seq());
-                socket.send_param.window += item.packet.payload().len() as u16;
                 self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
+                if newest_delivery.is_none_or(|newest| {
+                    (item.delivery.delivered, item.delivery.sent_time)
+                        > (newest.delivered, newest.sent_time)
+                }) {
+                    newest_delivery = Some(item.delivery);
+                }
+                latest_acked = Some(item);
             } else {
                 // ackされてないので戻すす
                 socket.retransmission_queue.push_front(item);
                 break;
             }
         }
+        let now = self.clock.now();
+        // 再送したセグメントはどの送信に対するACKか区別できないのでRTTを測定しない(Karnのアルゴリズム)
+        let rtt = latest_acked
+            .filter(|item| item.transmission_count == 1)
+            .map(|item| {
+                now.duration_since(item.latest_transmission_time)
+                    .unwrap_or_default()
+            });
+        if let Some(rtt) = rtt {
+            socket.rtt.update(rtt);
+        }
+        socket.dup_ack_count = 0;
+        socket.bytes_acked += acked as u64;
+        let rate = socket
+            .delivery_rate
+            .on_ack(acked, newest_delivery, rtt, now);
+        let sample = AckSample {
+            acked,
+            flight_size: socket.flight_size(),
+            rtt,
+            rate,
+            in_recovery: socket.recovery_point.is_some(),
+            now,
+        };
+        socket
+            .congestion_control
+            .on_ack(&mut socket.congestion_window, &sample);
+        if let Some(recovery_point) = socket.recovery_point {
+            if socket.send_param.unacked_seq >= recovery_point {
+                // リカバリ開始時に送信済みだったデータが全てACKされた
+                dbg!("fast recovery: done");
+                socket.recovery_point = None;
+                socket
+                    .congestion_control
+                    .on_recovery_end(&mut socket.congestion_window, sample.flight_size);
+            } else {
+                // 部分ACK。次のセグメントも失われているので、続けて再送する
+                self.retransmit_head(socket)?;
+            }
+        }
+        dbg!(
+            socket.congestion_window.cwnd,
+            socket.congestion_window.ssthresh
+        );
+        Ok(())
+    }
+
+    // 重複ACKの処理
+    // 同じ確認応答番号のACKが続けて届くのは、後続のセグメントは届いたがその前のセグメントが失われた場合
+    // 3回目の重複ACKでタイムアウトを待たずに先頭のセグメントを再送し(高速再送)、
+    // それまでに送信したデータが全てACKされるまで部分ACKのたびに再送を続ける(NewRenoの高速リカバリ)
+    // https://datatracker.ietf.org/doc/html/rfc5681#section-3.2
+    // https://datatracker.ietf.org/doc/html/rfc6582
+    fn duplicate_ack_handler(&self, socket: &mut Socket<D>, packet: &TCPPacket) -> Result<()> {
+        // データやSYN、FINを含むセグメントや、ACK待ちのデータがない場合は重複ACKとみなさない
+        if packet.get_flag() & tcpflags::ACK == 0
+            || packet.get_flag() & (tcpflags::SYN | tcpflags::FIN | tcpflags::RST) > 0
+            || !packet.payload().is_empty()
+            || socket.send_param.unacked_seq == socket.send_param.next
+        {
+            return Ok(());
+        }
+        socket.dup_ack_count += 1;
+        dbg!("duplicate ack", socket.dup_ack_count);
+        if socket.recovery_point.is_some() {
+            // 高速リカバリ中は、重複ACKが届くたびに新しいセグメントを送信できるようになる
+            socket
+                .congestion_control
+                .on_duplicate_ack(&mut socket.congestion_window);
+            self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
+        } else if socket.dup_ack_count == DUP_ACK_THRESHOLD {
+            dbg!("fast retransmit");
+            let flight_size = socket.flight_size();
+            socket.recovery_point = Some(socket.send_param.next);
+            socket
+                .congestion_control
+                .on_loss(&mut socket.congestion_window, flight_size);
+            self.retransmit_head(socket)?;
+        }
+        Ok(())
+    }
+
+    // 再送キューの先頭のセグメントを、タイムアウトを待たずに再送する
+    fn retransmit_head(&self, socket: &mut Socket<D>) -> Result<()> {
+        let flight_size = socket.flight_size();
+        if let Some(item) = socket.retransmission_queue.front_mut() {
+            socket
+                .sender
+                .send(&item.packet, socket.local_addr, socket.remote_addr)
+                .context("failed to retransmit")?;
+            item.transmission_count += 1;
+            item.latest_transmission_time = self.clock.now();
+            item.delivery = socket
+                .delivery_rate
+                .on_send(flight_size, item.latest_transmission_time);
+            socket.retransmissions += 1;
+        }
+        Ok(())
     }
 
     // リスニングソケットを生成してソケットIDを返す
-    pub fn listen(&self, local_addr: Ipv4Addr, local_port: u16) -> Result<SockID> {
-        let socket = Socket::new(
+    pub fn listen(&self, local_addr: IpAddr, local_port: u16) -> Result<SockID> {
+        let mut socket = Socket::new(
             local_addr,
-            UNDETERMINED_IP_ADDR, // まだ接続先IPアドレスは未定
+            undetermined_addr(local_addr), // まだ接続先IPアドレスは未定
             local_port,
             UNDETERMINED_PORT, // まだ接続先ポート番号は未定
             TcpStatus::Listen,
+            self.device.clone(),
+            self.clock.clone(),
         )?;
+        socket.congestion_control = self.new_congestion_control();
         let mut lock = self.sockets.write().unwrap();
         let sock_id = socket.get_sock_id();
         lock.insert(sock_id, socket);
@@ -502,15 +1077,21 @@ impl TCP {
     pub fn accept(&self, sock_id: SockID) -> Result<SockID> {
         // Queueを介さずにcond_varでSockIDを送れたりしないんだろうか...と思ったが、CondVarで扱うのはbooleanだった。
         // アクターモデルのようにイベントと一緒に変数を送れるたりしたら良さそうだね
-        self.wait_event(sock_id, TCPEventKind::ConnectionCompleted);
-
-        let mut table = self.sockets.write().unwrap();
-        Ok(table
-            .get_mut(&sock_id)
-            .context(format!("no such socket: {:?}", sock_id))?
-            .connected_connection_queue
-            .pop_front()
-            .context("no connected socket")?)
+        loop {
+            let mut table = self.sockets.write().unwrap();
+            // 同時に複数の接続が完了した場合イベントはまとめられるため、先にキューを確認する
+            if let Some(id) = table
+                .get_mut(&sock_id)
+                .ok_or_else(|| self.socket_not_found(sock_id))?
+                .connected_connection_queue
+                .pop_front()
+            {
+                return Ok(id);
+            }
+            // ロックを外してイベントの待機。受信スレッドがロックを取得できるようにするため。
+            drop(table);
+            self.wait_event(sock_id, TCPEventKind::ConnectionCompleted)?;
+        }
     }
 
     fn select_unused_port(&self, rng: &mut ThreadRng) -> Result<u16> {
@@ -525,30 +1106,59 @@ impl TCP {
     }
 
     // ターゲットに接続し、接続済みソケットのIDを返す
-    pub fn connect(&self, addr: Ipv4Addr, port: u16) -> Result<SockID> {
+    pub fn connect(&self, addr: IpAddr, port: u16) -> Result<SockID> {
+        // コネクションを一意に特定するために未使用のポートを選択する
+        let local_port = self.select_unused_port(&mut rand::thread_rng())?;
+        self.connect_from(local_port, addr, port)
+    }
+
+    // 送信元ポートを指定してターゲットに接続し、接続済みソケットのIDを返す
+    // 相手も同時にこちらへ接続を試みた場合は同時オープンとなり、同じ1つのコネクションが確立される
+    pub fn connect_from(&self, local_port: u16, addr: IpAddr, port: u16) -> Result<SockID> {
+        let sock_id = self.start_connect(local_port, addr, port)?;
+        if let Err(error) = self.wait_event(sock_id, TCPEventKind::ConnectionCompleted) {
+            // ソケットIDは呼び出し元に渡らずcloseされないので、ここで破棄の記録を消す
+            self.aborted.lock().unwrap().remove(&sock_id);
+            return Err(error);
+        }
+        Ok(sock_id)
+    }
+
+    // SYNを送信してSYNSENTのソケットを登録し、接続の完了を待たずにソケットのIDを返す
+    pub(crate) fn start_connect(&self, local_port: u16, addr: IpAddr, port: u16) -> Result<SockID> {
         let mut rng = rand::thread_rng();
         let mut socket = Socket::new(
-            get_source_addr_to(addr)?,
+            self.device.source_addr_to(addr)?,
             addr,
-            // コネクションを一意に特定するために未使用のポートを選択する
-            self.select_unused_port(&mut rng)?,
+            local_port,
             port,
             TcpStatus::SynSent,
+            self.device.clone(),
+            self.clock.clone(),
         )?;
+        socket.congestion_control = self.new_congestion_control();
         // 初期シーケンス番号は乱数で選ぶ
         // - 以前に利用されたコネクションのシーケンス番号との混乱を避けるため
         // - TCPシーケンス番号予測攻撃を避けるため
-        socket.send_param.initial_seq = rng.gen_range(1..1<<31);
-        socket.send_tcp_packet(socket.send_param.initial_seq, 0, tcpflags::SYN, &[])?;
+        // シーケンス番号は折り返して比較されるので、32ビットの全範囲から選んでよい
+        socket.send_param.initial_seq = SeqNum::new(rng.gen());
+        // SYN|ACKがソケットの登録より先に処理されないよう、ロックを取得してからSYNを送信する
+        let mut table = self.sockets.write().unwrap();
+        if table.contains_key(&socket.get_sock_id()) {
+            anyhow::bail!("address already in use: {:?}", socket.get_sock_id());
+        }
+        socket.send_tcp_packet(
+            socket.send_param.initial_seq,
+            SeqNum::default(),
+            tcpflags::SYN,
+            &[],
+        )?;
         socket.send_param.unacked_seq = socket.send_param.initial_seq;
         // SYNセグメントはペイロードを持たないが、確認応答を受けるために１つインクリメントする
         socket.send_param.next = socket.send_param.initial_seq + 1;
-        let mut table = self.sockets.write().unwrap();
         let sock_id = socket.get_sock_id();
+        self.aborted.lock().unwrap().remove(&sock_id);
         table.insert(sock_id, socket);
-        // ロックを外してイベントの待機。受信スレッドがロックを取得できるようにするため。
-        drop(table);
-        self.wait_event(sock_id, TCPEventKind::ConnectionCompleted);
         Ok(sock_id)
     }
 
@@ -558,24 +1168,45 @@ impl TCP {
         let mut table = self.sockets.write().unwrap();
         let mut socket = table
             .get_mut(&sock_id)
-            .context(format!("no such socket: {:?}", sock_id))?;
+            .ok_or_else(|| self.socket_not_found(sock_id))?;
         let mut received_size = socket.recv_buffer.len() - socket.recv_param.window as usize;
         while received_size == 0 {
+            // 相手からFINを受信済みであれば、これ以上データは届かない
+            if matches!(
+                socket.status,
+                TcpStatus::CloseWait
+                    | TcpStatus::LastAck
+                    | TcpStatus::Closing
+                    | TcpStatus::TimeWait
+            ) {
+                return Ok(0);
+            }
             // ロックを外してイベントの待機する。
             // 受信スレッドがロックを取得できるようにするため。
             drop(table);
             dbg!("waiting incoming data");
-            self.wait_event(sock_id, TCPEventKind::DataArrived);
+            self.wait_event(sock_id, TCPEventKind::DataArrived)?;
             table = self.sockets.write().unwrap();
             socket = table
                 .get_mut(&sock_id)
-                .context(format!("no such socket: {:?}", sock_id))?;
+                .ok_or_else(|| self.socket_not_found(sock_id))?;
             received_size = socket.recv_buffer.len() - socket.recv_param.window as usize;
         }
         let copy_size = cmp::min(buffer.len(), received_size);
         buffer[..copy_size].copy_from_slice(&socket.recv_buffer[..copy_size]);
         socket.recv_buffer.copy_within(copy_size.., 0);
+        let previous_window = socket.recv_param.window as usize;
         socket.recv_param.window += copy_size as u16;
+        // 受信ウィンドウがMSS未満まで閉じていた場合は、空きができたことを相手に知らせる
+        // 知らせないと、相手はウィンドウが開くのを待ち続けてしまう
+        if previous_window < MSS && socket.recv_param.window as usize >= MSS {
+            socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
+            )?;
+        }
         Ok(copy_size)
     }
 
@@ -587,27 +1218,43 @@ impl TCP {
             let mut table = self.sockets.write().unwrap();
             let mut socket = table
                 .get_mut(&sock_id)
-                .context(format!("no such socket: {:?}", sock_id))?;
+                .ok_or_else(|| self.socket_not_found(sock_id))?;
             let mut send_size = cmp::min(
-                MSS,
-                cmp::min(socket.send_param.window as usize, buffer.len() - cursor),
+                socket.congestion_window.mss,
+                cmp::min(socket.usable_window(), buffer.len() - cursor),
             );
             while send_size == 0 {
                 dbg!("unable to slide send window");
                 // ロックを外してイベントの待機．受信スレッドがロックを取得できるようにするため．
                 drop(table);
-                self.wait_event(sock_id, TCPEventKind::Acked);
+                self.wait_event(sock_id, TCPEventKind::Acked)?;
                 table = self.sockets.write().unwrap();
                 socket = table
                     .get_mut(&sock_id)
-                    .context(format!("no such socket: {:?}", sock_id))?;
+                    .ok_or_else(|| self.socket_not_found(sock_id))?;
                 // 送信サイズを再計算する
                 send_size = cmp::min(
-                    MSS,
-                    cmp::min(socket.send_param.window as usize, buffer.len() - cursor),
+                    socket.congestion_window.mss,
+                    cmp::min(socket.usable_window(), buffer.len() - cursor),
                 );
             }
-            dbg!("current window size", socket.send_param.window);
+            // ペーシング。前のセグメントから送信間隔が経っていなければ、ロックを外して待ってからやり直す
+            // 間隔はタイマーと同じ時計で計り、sendの呼び出しをまたいでも保つ
+            let now = self.clock.now();
+            if let Some(wait) = socket
+                .next_send_time
+                .and_then(|next_send_time| next_send_time.duration_since(now).ok())
+                .filter(|wait| !wait.is_zero())
+            {
+                drop(table);
+                self.clock.sleep(wait);
+                continue;
+            }
+            dbg!(
+                "current window size",
+                socket.congestion_window.cwnd,
+                socket.send_param.window
+            );
             socket.send_tcp_packet(
                 socket.send_param.next,
                 socket.recv_param.next,
@@ -616,7 +1263,19 @@ impl TCP {
             )?;
             cursor += send_size;
             socket.send_param.next += send_size as u32;
-            socket.send_param.window -= send_size as u16;
+            // 送信するデータを使い切ってもウィンドウに余裕があれば、この後の配送レートは帯域ではなく
+            // アプリケーションに制限された値になる
+            let flight_size = socket.flight_size();
+            if cursor == buffer.len() && flight_size < socket.congestion_window.cwnd {
+                socket.delivery_rate.on_app_limited(flight_size);
+            }
+            // 輻輳制御アルゴリズムが送信レートを指定していれば、その間隔を空けて次のセグメントを送信する(ペーシング)
+            socket.next_send_time = match socket.congestion_control.pacing_rate() {
+                Some(rate) if rate > 0 => {
+                    Some(now + Duration::from_secs_f64(send_size as f64 / rate as f64))
+                }
+                _ => None,
+            };
             // 少しの間ロックを外して待機し、受信スレッドがACKを受信できるようにしている。
             // send_windowが0になるまで送り続け、送信がブロックされる確率を下げるため
             drop(table);
@@ -626,32 +1285,12 @@ impl TCP {
     }
 }
 
-// 宛先IPアドレスに対する送信元インタフェースのIPアドレスを取得する
-// iproute2-ss170129で動作確認。バージョンによって挙動が変わるかも。
-//
-// $ ip -V
-// ip utility, iproute2-ss200127
-// $ sudo ip netns exec host2 ip route get 10.0.0.1
-// 10.0.0.1 via 10.0.1.254 dev host2-veth1 src 10.0.1.1 uid 0
-// cache
-fn get_source_addr_to(addr: Ipv4Addr) -> Result<Ipv4Addr> {
-    // ipコマンドを利用して、指定の宛先に対するローカルのIPアドレスを取得する
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(format!("ip route get {} | grep src", addr))
-        .output()?;
-    // src句の後ろにある文字列が送信元IPアドレス
-    let mut output = str::from_utf8(&output.stdout)?
-        .trim()
-        .split_ascii_whitespace();
-    while let Some(s) = output.next() {
-        if s == "src" {
-            break;
-        }
-    }
-    let ip = output.next().context("failed to get src ip")?;
-    dbg!("source addr", ip);
-    ip.parse().context("failed to parse source ip")
+// リスニングソケットの接続先アドレス。ローカルアドレスと同じアドレスファミリの未指定アドレスを使う
+fn undetermined_addr(local_addr: IpAddr) -> IpAddr {
+    match local_addr {
+        IpAddr::V4(_) => IpAddr::V4(UNDETERMINED_IPV4_ADDR),
+        IpAddr::V6(_) => IpAddr::V6(UNDETERMINED_IPV6_ADDR),
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -0,0 +1,223 @@
+use crate::seq::SeqNum;
+
+// TCPオプション
+// https://datatracker.ietf.org/doc/html/rfc9293#section-3.1
+//
+// EOLとNOPは種別の1バイトのみ、それ以外は種別、長さ(種別と長さを含むバイト数)、データの順に並ぶ
+//   +--------+--------+---------...
+//   |  Kind  | Length |  Data
+//   +--------+--------+---------...
+// ヘッダ長(データオフセット)が4バイト単位なので、オプションの後ろは0(EOL)で埋める
+
+const KIND_EOL: u8 = 0;
+const KIND_NOP: u8 = 1;
+const KIND_MSS: u8 = 2;
+const KIND_WINDOW_SCALE: u8 = 3;
+const KIND_SACK_PERMITTED: u8 = 4;
+const KIND_SACK: u8 = 5;
+const KIND_TIMESTAMPS: u8 = 8;
+
+// オプションに使えるのは、データオフセットの最大値(15)から固定長のヘッダを除いた40バイト
+pub const MAX_OPTIONS_SIZE: usize = 40;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TcpOption {
+    // オプションリストの終わり。以降はパディング
+    Eol,
+    // オプションの境界を揃えるための詰め物
+    Nop,
+    // 受信できるセグメントの最大サイズ。SYNでのみ送る (RFC 9293)
+    Mss(u16),
+    // 受信ウィンドウを左シフトするビット数 (RFC 7323)
+    WindowScale(u8),
+    // SACKを利用できることを通知する。SYNでのみ送る (RFC 2018)
+    SackPermitted,
+    // 受信済みの不連続なデータの範囲(先頭, 末尾の次) (RFC 2018)
+    Sack(Vec<(SeqNum, SeqNum)>),
+    // 送信時刻と、相手から最後に受け取った送信時刻 (RFC 7323)
+    Timestamps { value: u32, echo_reply: u32 },
+    // 対応していない種別、または長さが不正なオプション。そのまま保持する
+    Unknown { kind: u8, data: Vec<u8> },
+}
+
+impl TcpOption {
+    // 種別と長さを含めたバイト数
+    pub fn size(&self) -> usize {
+        match self {
+            TcpOption::Eol | TcpOption::Nop => 1,
+            TcpOption::Mss(_) => 4,
+            TcpOption::WindowScale(_) => 3,
+            TcpOption::SackPermitted => 2,
+            TcpOption::Sack(blocks) => 2 + 8 * blocks.len(),
+            TcpOption::Timestamps { .. } => 10,
+            TcpOption::Unknown { data, .. } => 2 + data.len(),
+        }
+    }
+
+    pub fn write_to(&self, buffer: &mut Vec<u8>) {
+        match self {
+            TcpOption::Eol => buffer.push(KIND_EOL),
+            TcpOption::Nop => buffer.push(KIND_NOP),
+            TcpOption::Mss(mss) => {
+                buffer.extend_from_slice(&[KIND_MSS, 4]);
+                buffer.extend_from_slice(&mss.to_be_bytes());
+            }
+            TcpOption::WindowScale(shift) => {
+                buffer.extend_from_slice(&[KIND_WINDOW_SCALE, 3, *shift]);
+            }
+            TcpOption::SackPermitted => buffer.extend_from_slice(&[KIND_SACK_PERMITTED, 2]),
+            TcpOption::Sack(blocks) => {
+                buffer.extend_from_slice(&[KIND_SACK, self.size() as u8]);
+                for (left, right) in blocks {
+                    buffer.extend_from_slice(&left.value().to_be_bytes());
+                    buffer.extend_from_slice(&right.value().to_be_bytes());
+                }
+            }
+            TcpOption::Timestamps { value, echo_reply } => {
+                buffer.extend_from_slice(&[KIND_TIMESTAMPS, 10]);
+                buffer.extend_from_slice(&value.to_be_bytes());
+                buffer.extend_from_slice(&echo_reply.to_be_bytes());
+            }
+            TcpOption::Unknown { kind, data } => {
+                buffer.extend_from_slice(&[*kind, self.size() as u8]);
+                buffer.extend_from_slice(data);
+            }
+        }
+    }
+
+    // 種別ごとに決められた長さと一致しなければUnknownとして扱う
+    fn from_kind(kind: u8, data: &[u8]) -> Self {
+        let u16_at = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+        let u32_at =
+            |i: usize| u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        match (kind, data.len()) {
+            (KIND_MSS, 2) => TcpOption::Mss(u16_at(0)),
+            (KIND_WINDOW_SCALE, 1) => TcpOption::WindowScale(data[0]),
+            (KIND_SACK_PERMITTED, 0) => TcpOption::SackPermitted,
+            (KIND_SACK, len) if len > 0 && len % 8 == 0 => TcpOption::Sack(
+                (0..len)
+                    .step_by(8)
+                    .map(|i| (SeqNum::new(u32_at(i)), SeqNum::new(u32_at(i + 4))))
+                    .collect(),
+            ),
+            (KIND_TIMESTAMPS, 8) => TcpOption::Timestamps {
+                value: u32_at(0),
+                echo_reply: u32_at(4),
+            },
+            _ => TcpOption::Unknown {
+                kind,
+                data: data.to_vec(),
+            },
+        }
+    }
+}
+
+// ヘッダのオプション部分を解析する
+// EOLで終わり、それ以降のパディングは含めない。長さが不正で次のオプションの位置が分からなくなった場合は、
+// それまでに解析できたオプションを返す
+pub fn parse(mut bytes: &[u8]) -> Vec<TcpOption> {
+    let mut options = Vec::new();
+    while let Some(&kind) = bytes.first() {
+        match kind {
+            KIND_EOL => break,
+            KIND_NOP => {
+                options.push(TcpOption::Nop);
+                bytes = &bytes[1..];
+            }
+            _ => {
+                let len = match bytes.get(1) {
+                    Some(&len) if len >= 2 && len as usize <= bytes.len() => len as usize,
+                    _ => {
+                        dbg!("malformed tcp option", kind);
+                        break;
+                    }
+                };
+                options.push(TcpOption::from_kind(kind, &bytes[2..len]));
+                bytes = &bytes[len..];
+            }
+        }
+    }
+    options
+}
+
+// オプションを並べ、4バイト単位になるよう0で埋める
+pub fn serialize(options: &[TcpOption]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    for option in options {
+        option.write_to(&mut buffer);
+    }
+    buffer.resize(buffer.len().next_multiple_of(4), KIND_EOL);
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_all_kinds() {
+        let options = vec![
+            TcpOption::Mss(1460),
+            TcpOption::SackPermitted,
+            TcpOption::Timestamps {
+                value: 7,
+                echo_reply: 9,
+            },
+            TcpOption::Nop,
+            TcpOption::WindowScale(7),
+            TcpOption::Sack(vec![
+                (SeqNum::new(1), SeqNum::new(2)),
+                (SeqNum::new(u32::MAX), SeqNum::new(20)),
+            ]),
+            TcpOption::Unknown {
+                kind: 30,
+                data: vec![1, 2, 3],
+            },
+        ];
+        let bytes = serialize(&options);
+        let size: usize = options.iter().map(TcpOption::size).sum();
+        assert_eq!(size, 4 + 2 + 10 + 1 + 3 + 18 + 5);
+        // 4バイト単位になるようEOLで埋められ、解析する時にはパディングを含めない
+        assert_eq!(bytes.len(), 44);
+        assert!(bytes[size..].iter().all(|&byte| byte == KIND_EOL));
+        assert_eq!(parse(&bytes), options);
+    }
+
+    #[test]
+    fn parse_stops_at_eol() {
+        assert_eq!(parse(&[1, 0, 2, 4, 5, 180]), vec![TcpOption::Nop]);
+        assert_eq!(parse(&[]), vec![]);
+    }
+
+    #[test]
+    fn wrong_length_is_kept_as_unknown() {
+        // MSSの長さは4バイトと決まっている
+        assert_eq!(
+            parse(&[2, 3, 0, 1, 1]),
+            vec![
+                TcpOption::Unknown {
+                    kind: 2,
+                    data: vec![0]
+                },
+                TcpOption::Nop,
+                TcpOption::Nop,
+            ]
+        );
+        // SACKのブロックは8バイト単位
+        assert_eq!(
+            parse(&[5, 6, 0, 0, 0, 1]),
+            vec![TcpOption::Unknown {
+                kind: 5,
+                data: vec![0, 0, 0, 1]
+            }]
+        );
+    }
+
+    #[test]
+    fn malformed_length_stops_parsing() {
+        // 長さが2未満か、残りのバイト数を超える場合は次のオプションの位置が分からない
+        assert_eq!(parse(&[1, 2, 1, 1]), vec![TcpOption::Nop]);
+        assert_eq!(parse(&[1, 2, 9, 1]), vec![TcpOption::Nop]);
+        assert_eq!(parse(&[2]), vec![]);
+    }
+}
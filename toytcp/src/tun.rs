@@ -0,0 +1,88 @@
+use crate::device::Device;
+use crate::ip;
+use crate::packet::TCPPacket;
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::os::unix::io::AsRawFd;
+
+const TUN_DEVICE_PATH: &str = "/dev/net/tun";
+// linux/if_tun.h: _IOW('T', 202, int)
+const TUNSETIFF: u64 = 0x400454ca;
+const TUN_BUFFER_SIZE: usize = 65535;
+
+// ioctlに渡すstruct ifreqのうちTUNSETIFFで使用する部分
+#[repr(C)]
+struct IfReq {
+    name: [u8; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _padding: [u8; 22],
+}
+
+// LinuxのTUNインタフェースを利用するバックエンド
+// カーネルが所有していないアドレスをtoytcp自身が持ち、IPヘッダも自前で組み立てる。
+// カーネルは自分宛てでないセグメントにRSTを返さないため、iptablesでRSTを破棄する必要がない。
+pub struct TunDevice {
+    file: File,
+    addr: IpAddr,
+}
+
+impl TunDevice {
+    // TUNインタフェースnameに接続し、addrを自身のアドレスとして応答する
+    // インタフェースは事前に作成し、addr宛ての経路をこのインタフェースに向けておく
+    pub fn new(name: &str, addr: IpAddr) -> Result<Self> {
+        if name.len() >= libc::IFNAMSIZ {
+            anyhow::bail!("interface name too long: {}", name);
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(TUN_DEVICE_PATH)
+            .context(format!("failed to open {}", TUN_DEVICE_PATH))?;
+        let mut req = IfReq {
+            name: [0; libc::IFNAMSIZ],
+            // パケット情報ヘッダを付加せず、IPパケットをそのまま読み書きする
+            flags: (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short,
+            _padding: [0; 22],
+        };
+        req.name[..name.len()].copy_from_slice(name.as_bytes());
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &mut req) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error())
+                .context(format!("failed to attach tun interface: {}", name));
+        }
+        Ok(Self { file, addr })
+    }
+}
+
+impl Device for TunDevice {
+    fn send(&self, packet: &TCPPacket, src: IpAddr, dst: IpAddr) -> Result<usize> {
+        let ip_packet = ip::build_ip_packet(packet, src, dst)?;
+        // TUNデバイスへの1回の書き込みが1つのIPパケットになる
+        (&self.file)
+            .write(&ip_packet)
+            .context(format!("failed to send: \n{:?}", packet))
+    }
+
+    fn recv(&self) -> Result<(TCPPacket, IpAddr, IpAddr)> {
+        let mut buffer = vec![0; TUN_BUFFER_SIZE];
+        loop {
+            // 1回の読み込みで1つのIPパケットを受信する
+            let size = (&self.file).read(&mut buffer)?;
+            let (packet, src, dst) = match ip::parse_ip_packet(&buffer[..size]) {
+                Some(p) => p,
+                None => continue,
+            };
+            // 自身のアドレス宛て以外は無視
+            if dst != self.addr {
+                continue;
+            }
+            return Ok((packet, src, dst));
+        }
+    }
+
+    fn source_addr_to(&self, _addr: IpAddr) -> Result<IpAddr> {
+        Ok(self.addr)
+    }
+}
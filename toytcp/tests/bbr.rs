@@ -0,0 +1,68 @@
+mod common;
+
+use common::{wait_until, Peer, ACK, MSS, PEER_ADDR, PEER_ISN, PEER_PORT, PORT, SYN, TOYTCP_ADDR};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use toytcp::bbr::Bbr;
+use toytcp::clock::VirtualClock;
+use toytcp::sim::SimNetwork;
+use toytcp::tcp::TCP;
+
+// ペーシングの間隔はタイマーと同じ時計で計る。仮想時計を進めるまで次のセグメントは送信されない
+#[test]
+fn pacing_waits_on_clock() {
+    let clock = Arc::new(VirtualClock::new());
+    let net = SimNetwork::new();
+    let tcp = TCP::with_clock(
+        net.attach(IpAddr::from(TOYTCP_ADDR)).unwrap(),
+        clock.clone(),
+    );
+    let peer = Peer::attach(&net);
+
+    let cloned_tcp = tcp.clone();
+    let handle =
+        thread::spawn(move || cloned_tcp.connect_from(PORT, IpAddr::from(PEER_ADDR), PEER_PORT));
+    let syn = peer.recv();
+    peer.send(PEER_ISN, syn.get_seq() + 1, SYN | ACK);
+    assert_eq!(peer.recv().get_flag(), ACK);
+    let sock = handle.join().unwrap().unwrap();
+    tcp.set_congestion_control(sock, Bbr::new()).unwrap();
+
+    // 1セグメントを100msでACKし、配送レートを1460バイト/100msと測定させる
+    tcp.send(sock, &[0; MSS]).unwrap();
+    let segment = peer.recv();
+    clock.advance(Duration::from_millis(100));
+    peer.send(PEER_ISN + 1, segment.get_seq() + MSS as u32, ACK);
+    wait_until(|| tcp.stats(sock).unwrap().pacing_rate.is_some());
+    // STARTUPのpacing_gainは2.885なので、1セグメントの送信間隔は約35ms
+    let pacing_rate = tcp.stats(sock).unwrap().pacing_rate.unwrap();
+    assert_eq!(pacing_rate, (2.885 * 14600.0) as u64);
+
+    let cloned_tcp = tcp.clone();
+    let handle = thread::spawn(move || cloned_tcp.send(sock, &[0; MSS * 2]));
+    let first = peer.recv();
+    // 実時間では待っても、時計が進まなければ2つ目のセグメントは送信されない
+    assert!(peer
+        .received
+        .recv_timeout(Duration::from_millis(100))
+        .is_err());
+    clock.advance(Duration::from_millis(40));
+    let second = peer.recv();
+    assert_eq!(second.get_seq(), first.get_seq() + MSS as u32);
+    // 最後のセグメントの後は待たずに戻る
+    handle.join().unwrap().unwrap();
+
+    // 送信間隔はsendの呼び出しをまたいでも保たれる
+    let cloned_tcp = tcp.clone();
+    let handle = thread::spawn(move || cloned_tcp.send(sock, &[0; MSS]));
+    assert!(peer
+        .received
+        .recv_timeout(Duration::from_millis(100))
+        .is_err());
+    clock.advance(Duration::from_millis(40));
+    let third = peer.recv();
+    assert_eq!(third.get_seq(), second.get_seq() + MSS as u32);
+    handle.join().unwrap().unwrap();
+}
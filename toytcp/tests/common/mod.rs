@@ -0,0 +1,92 @@
+// 統合テストで共有する定数と、セグメントを直接送受信する相手
+// テストごとに使う項目が異なるので、使われない項目の警告は出さない
+#![allow(dead_code)]
+
+use std::net::IpAddr;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use toytcp::clock::VirtualClock;
+use toytcp::device::Device;
+use toytcp::packet::{TCPPacket, TCPPacketBuilder};
+use toytcp::seq::SeqNum;
+use toytcp::sim::{SimDevice, SimNetwork};
+use toytcp::tcpoption::TcpOption;
+
+// tcpflagsは公開されていないので、テストで使うフラグを定義する
+pub const ACK: u8 = 1 << 4;
+pub const RST: u8 = 1 << 2;
+pub const SYN: u8 = 1 << 1;
+
+pub const TOYTCP_ADDR: [u8; 4] = [10, 0, 0, 1];
+pub const PEER_ADDR: [u8; 4] = [10, 0, 1, 1];
+pub const PORT: u16 = 40000;
+pub const PEER_PORT: u16 = 50000;
+pub const PEER_ISN: u32 = 1000;
+pub const MSS: usize = 1460;
+
+// toytcpが送信したセグメントをチャネルで受け取る相手
+pub struct Peer {
+    pub device: Arc<SimDevice>,
+    pub received: Receiver<TCPPacket>,
+}
+
+impl Peer {
+    pub fn attach(net: &SimNetwork) -> Self {
+        let device = Arc::new(net.attach(IpAddr::from(PEER_ADDR)).unwrap());
+        let (sender, received) = mpsc::channel();
+        let cloned_device = device.clone();
+        thread::spawn(move || {
+            while let Ok((packet, _, _)) = cloned_device.recv() {
+                if sender.send(packet).is_err() {
+                    return;
+                }
+            }
+        });
+        Self { device, received }
+    }
+
+    pub fn send(&self, seq: u32, ack: SeqNum, flag: u8) {
+        let (src_addr, dst_addr) = (IpAddr::from(PEER_ADDR), IpAddr::from(TOYTCP_ADDR));
+        let mut builder = TCPPacketBuilder::new()
+            .src(PEER_PORT)
+            .dest(PORT)
+            .seq(SeqNum::new(seq))
+            .ack(ack)
+            .flag(flag)
+            .window_size(4380);
+        // SYNではMSSを通知する。通知しなければtoytcpは536バイトずつ送信する
+        if flag & SYN > 0 {
+            builder = builder.option(TcpOption::Mss(MSS as u16));
+        }
+        let packet = builder.build(src_addr, dst_addr).unwrap();
+        self.device.send(&packet, src_addr, dst_addr).unwrap();
+    }
+
+    pub fn recv(&self) -> TCPPacket {
+        self.received.recv_timeout(Duration::from_secs(5)).unwrap()
+    }
+
+    // 次のセグメントが送信されるまで、仮想時計を少しずつ進める。タイムアウトによる再送を待つのに使う
+    pub fn recv_advancing(&self, clock: &VirtualClock) -> TCPPacket {
+        for _ in 0..1000 {
+            clock.advance(Duration::from_millis(100));
+            if let Ok(packet) = self.received.recv_timeout(Duration::from_millis(10)) {
+                return packet;
+            }
+        }
+        panic!("no segment was sent");
+    }
+}
+
+// 受信スレッドがセグメントを処理するのを待つ
+pub fn wait_until(condition: impl Fn() -> bool) {
+    for _ in 0..500 {
+        if condition() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    panic!("condition was not met");
+}
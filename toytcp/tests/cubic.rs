@@ -0,0 +1,97 @@
+mod common;
+
+use common::{Peer, ACK, MSS, PEER_ADDR, PEER_ISN, PEER_PORT, PORT, SYN, TOYTCP_ADDR};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::thread;
+use toytcp::clock::VirtualClock;
+use toytcp::congestion::CongestionInfo;
+use toytcp::cubic::Cubic;
+use toytcp::seq::SeqNum;
+use toytcp::sim::SimNetwork;
+use toytcp::tcp::TCP;
+
+// 同じセグメントのタイムアウトが続いても、W_maxやssthreshを下げるのは最初の1回だけ
+#[test]
+fn repeated_timeout_reduces_window_once() {
+    let clock = Arc::new(VirtualClock::new());
+    let net = SimNetwork::new();
+    let tcp = TCP::with_clock(
+        net.attach(IpAddr::from(TOYTCP_ADDR)).unwrap(),
+        clock.clone(),
+    );
+    let peer = Peer::attach(&net);
+
+    let cloned_tcp = tcp.clone();
+    let handle =
+        thread::spawn(move || cloned_tcp.connect_from(PORT, IpAddr::from(PEER_ADDR), PEER_PORT));
+    let syn = peer.recv();
+    peer.send(PEER_ISN, syn.get_seq() + 1, SYN | ACK);
+    assert_eq!(peer.recv().get_flag(), ACK);
+    let sock = handle.join().unwrap().unwrap();
+    tcp.set_congestion_control(sock, Cubic::new()).unwrap();
+
+    // 初期ウィンドウの3セグメントを送信し、どれにもACKを返さない
+    tcp.send(sock, &[0; MSS * 3]).unwrap();
+    let head = peer.recv().get_seq();
+    peer.recv();
+    peer.recv();
+
+    // 先頭のセグメントが2回タイムアウトするまで時計を進める
+    for _ in 0..2 {
+        let retransmitted = peer.recv_advancing(&clock);
+        assert_eq!(retransmitted.get_seq(), head);
+    }
+
+    let stats = tcp.stats(sock).unwrap();
+    assert_eq!(stats.retransmissions, 2);
+    assert_eq!(stats.cwnd, MSS);
+    // 最初のタイムアウトの時点のcwnd(3セグメント)をbeta_cubic倍したまま
+    assert_eq!(stats.ssthresh, MSS * 3 * 7 / 10);
+    match stats.congestion {
+        CongestionInfo::Cubic(info) => {
+            assert_eq!(info.w_max, 3.0);
+            assert_eq!(info.w_last_max, 3.0);
+        }
+        info => panic!("unexpected info {:?}", info),
+    }
+}
+
+// デフォルトのアルゴリズムは、変更した後にconnectしたソケットから使われる
+#[test]
+fn default_congestion_control_applies_to_new_sockets() {
+    let net = SimNetwork::new();
+    let tcp = TCP::with_device(net.attach(IpAddr::from(TOYTCP_ADDR)).unwrap());
+    let peer = Peer::attach(&net);
+    let before = tcp.listen(IpAddr::from(TOYTCP_ADDR), PORT + 1).unwrap();
+    tcp.set_default_congestion_control(Cubic::new());
+
+    let cloned_tcp = tcp.clone();
+    let handle =
+        thread::spawn(move || cloned_tcp.connect_from(PORT, IpAddr::from(PEER_ADDR), PEER_PORT));
+    let syn = peer.recv();
+    peer.send(PEER_ISN, syn.get_seq() + 1, SYN | ACK);
+    assert_eq!(peer.recv().get_flag(), ACK);
+    let sock = handle.join().unwrap().unwrap();
+
+    assert_eq!(tcp.stats(sock).unwrap().congestion_control, "cubic");
+    assert_eq!(tcp.stats(before).unwrap().congestion_control, "newreno");
+}
+
+// 受け入れたソケットは、リスニングソケットのアルゴリズムを引き継ぐ
+#[test]
+fn accepted_socket_inherits_congestion_control() {
+    let net = SimNetwork::new();
+    let tcp = TCP::with_device(net.attach(IpAddr::from(TOYTCP_ADDR)).unwrap());
+    let peer = Peer::attach(&net);
+    let listening = tcp.listen(IpAddr::from(TOYTCP_ADDR), PORT).unwrap();
+    tcp.set_congestion_control(listening, Cubic::new()).unwrap();
+
+    peer.send(PEER_ISN, SeqNum::new(0), SYN);
+    let syn_ack = peer.recv();
+    assert_eq!(syn_ack.get_flag(), SYN | ACK);
+    peer.send(PEER_ISN + 1, syn_ack.get_seq() + 1, ACK);
+    let sock = tcp.accept(listening).unwrap();
+
+    assert_eq!(tcp.stats(sock).unwrap().congestion_control, "cubic");
+}
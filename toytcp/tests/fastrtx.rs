@@ -0,0 +1,47 @@
+mod common;
+
+use common::{Peer, ACK, MSS, PEER_ADDR, PEER_ISN, PEER_PORT, PORT, SYN, TOYTCP_ADDR};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::thread;
+use toytcp::clock::VirtualClock;
+use toytcp::sim::SimNetwork;
+use toytcp::tcp::TCP;
+
+// タイムアウトで再送した後も、高速再送は最も古い未確認のセグメントを再送する
+#[test]
+fn fast_retransmit_after_timeout_resends_oldest_segment() {
+    let clock = Arc::new(VirtualClock::new());
+    let net = SimNetwork::new();
+    let tcp = TCP::with_clock(
+        net.attach(IpAddr::from(TOYTCP_ADDR)).unwrap(),
+        clock.clone(),
+    );
+    let peer = Peer::attach(&net);
+
+    let cloned_tcp = tcp.clone();
+    let handle =
+        thread::spawn(move || cloned_tcp.connect_from(PORT, IpAddr::from(PEER_ADDR), PEER_PORT));
+    let syn = peer.recv();
+    peer.send(PEER_ISN, syn.get_seq() + 1, SYN | ACK);
+    assert_eq!(peer.recv().get_flag(), ACK);
+    let sock = handle.join().unwrap().unwrap();
+
+    // 3セグメント分のデータを送信し、どれにもACKを返さない
+    tcp.send(sock, &[0; MSS * 3]).unwrap();
+    let head = peer.recv().get_seq();
+    peer.recv();
+    peer.recv();
+
+    // 先頭のセグメントがタイムアウトで再送されるまで時計を進める
+    let retransmitted = peer.recv_advancing(&clock);
+    assert_eq!(retransmitted.get_seq(), head);
+
+    // 再送も失われ、後続のセグメントは届いたとして重複ACKを返す
+    for _ in 0..3 {
+        peer.send(PEER_ISN + 1, head, ACK);
+    }
+    let fast_retransmitted = peer.recv();
+    assert_eq!(fast_retransmitted.get_seq(), head);
+    assert_eq!(tcp.stats(sock).unwrap().retransmissions, 2);
+}
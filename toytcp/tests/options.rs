@@ -0,0 +1,227 @@
+mod common;
+
+use common::{ACK, PEER_ADDR, PEER_PORT, PORT, SYN, TOYTCP_ADDR};
+use pnet::packet::Packet;
+use std::net::IpAddr;
+use toytcp::device::Device;
+use toytcp::packet::{TCPPacket, TCPPacketBuilder};
+use toytcp::seq::SeqNum;
+use toytcp::sim::SimNetwork;
+use toytcp::tcp::TCP;
+use toytcp::tcpoption::TcpOption;
+
+fn timestamps() -> TcpOption {
+    TcpOption::Timestamps {
+        value: 1,
+        echo_reply: 0,
+    }
+}
+
+#[test]
+fn payload_starts_after_options() {
+    let (src, dst) = (IpAddr::from(PEER_ADDR), IpAddr::from(TOYTCP_ADDR));
+    // オプションなし
+    let packet = TCPPacketBuilder::new()
+        .payload(b"hello")
+        .build(src, dst)
+        .unwrap();
+    assert_eq!(packet.get_data_offset(), 5);
+    assert_eq!(packet.payload(), b"hello");
+    assert!(packet.options().is_empty());
+
+    // 3バイトのオプションは4バイトに埋められる
+    let packet = TCPPacketBuilder::new()
+        .option(TcpOption::WindowScale(3))
+        .payload(b"hello")
+        .build(src, dst)
+        .unwrap();
+    assert_eq!(packet.get_data_offset(), 6);
+    assert_eq!(packet.header_len(), 24);
+    assert_eq!(packet.options(), vec![TcpOption::WindowScale(3)]);
+    assert_eq!(packet.payload(), b"hello");
+    assert!(packet.is_correct_checksum(src, dst));
+
+    // ちょうど40バイトのオプションでデータオフセットは最大値になる
+    let packet = TCPPacketBuilder::new()
+        .option(timestamps())
+        .option(timestamps())
+        .option(timestamps())
+        .option(timestamps())
+        .payload(b"hello")
+        .build(src, dst)
+        .unwrap();
+    assert_eq!(packet.get_data_offset(), 15);
+    assert_eq!(packet.options().len(), 4);
+    assert_eq!(packet.payload(), b"hello");
+
+    // 40バイトを超えるオプションは付けられない
+    let result = TCPPacketBuilder::new()
+        .option(timestamps())
+        .option(timestamps())
+        .option(timestamps())
+        .option(timestamps())
+        .option(TcpOption::Nop)
+        .build(src, dst);
+    assert!(result.is_err());
+}
+
+#[test]
+fn invalid_data_offset() {
+    let mut packet = TCPPacket::new(4);
+    assert!(packet.is_valid_data_offset());
+    assert_eq!(packet.payload().len(), 4);
+
+    // 固定長部分より小さい
+    packet.set_data_offset(4);
+    assert!(!packet.is_valid_data_offset());
+    assert_eq!(packet.header_len(), 20);
+
+    // セグメントの長さちょうどまでは正しい。ペイロードは空になる
+    packet.set_data_offset(6);
+    assert!(packet.is_valid_data_offset());
+    assert!(packet.payload().is_empty());
+
+    // セグメントの長さを超える。ヘッダ長はバッファの範囲に丸められる
+    packet.set_data_offset(15);
+    assert!(!packet.is_valid_data_offset());
+    assert_eq!(packet.header_len(), 24);
+    assert!(packet.payload().is_empty());
+
+    // 正しい値に戻せば、ペイロードの範囲も元に戻る
+    packet.set_data_offset(5);
+    assert_eq!(packet.get_data_offset(), 5);
+    assert_eq!(packet.payload().len(), 4);
+}
+
+// Linuxと同じようにオプションを付けて接続してくる相手
+#[test]
+fn accept_segments_with_options() {
+    let net = SimNetwork::new();
+    let (toytcp_addr, peer_addr) = (IpAddr::from(TOYTCP_ADDR), IpAddr::from(PEER_ADDR));
+    let tcp = TCP::with_device(net.attach(toytcp_addr).unwrap());
+    let peer = net.attach(peer_addr).unwrap();
+    let listening = tcp.listen(toytcp_addr, PORT).unwrap();
+
+    let syn = TCPPacketBuilder::new()
+        .src(PEER_PORT)
+        .dest(PORT)
+        .seq(SeqNum::new(100))
+        .flag(SYN)
+        .window_size(64240)
+        .option(TcpOption::Mss(1460))
+        .option(TcpOption::SackPermitted)
+        .option(timestamps())
+        .option(TcpOption::Nop)
+        .option(TcpOption::WindowScale(7))
+        .build(peer_addr, toytcp_addr)
+        .unwrap();
+    peer.send(&syn, peer_addr, toytcp_addr).unwrap();
+    let (syn_ack, _, _) = peer.recv().unwrap();
+    assert_eq!(syn_ack.options(), vec![TcpOption::Mss(1460)]);
+    assert!(syn_ack.payload().is_empty());
+
+    let segment = |seq: u32, payload: &[u8]| {
+        TCPPacketBuilder::new()
+            .src(PEER_PORT)
+            .dest(PORT)
+            .seq(SeqNum::new(seq))
+            .ack(syn_ack.get_seq() + 1)
+            .flag(ACK)
+            .window_size(64240)
+            .option(TcpOption::Nop)
+            .option(TcpOption::Nop)
+            .option(timestamps())
+            .payload(payload)
+            .build(peer_addr, toytcp_addr)
+            .unwrap()
+    };
+    peer.send(&segment(101, b""), peer_addr, toytcp_addr)
+        .unwrap();
+    let sock = tcp.accept(listening).unwrap();
+
+    // オプションの後ろからがデータ
+    peer.send(&segment(101, b"abcdef"), peer_addr, toytcp_addr)
+        .unwrap();
+    let mut buffer = [0; 16];
+    let nbytes = tcp.recv(sock, &mut buffer).unwrap();
+    assert_eq!(&buffer[..nbytes], b"abcdef");
+    let (ack, _, _) = peer.recv().unwrap();
+    assert_eq!(ack.get_ack(), SeqNum::new(107));
+
+    // データオフセットが不正なセグメントは破棄される
+    let mut invalid = segment(107, b"zz");
+    invalid.set_data_offset(15);
+    invalid.set_checksum(invalid.compute_checksum(peer_addr, toytcp_addr));
+    peer.send(&invalid, peer_addr, toytcp_addr).unwrap();
+    peer.send(&segment(107, b"gh"), peer_addr, toytcp_addr)
+        .unwrap();
+    let nbytes = tcp.recv(sock, &mut buffer).unwrap();
+    assert_eq!(&buffer[..nbytes], b"gh");
+}
+
+// 相手が通知したMSS、通知がなければ536バイトを超えないようにデータを分割して送信する
+#[test]
+fn send_segments_limited_by_peer_mss() {
+    for (options, mss) in [(vec![TcpOption::Mss(536)], 536), (vec![], 536)] {
+        let net = SimNetwork::new();
+        let (toytcp_addr, peer_addr) = (IpAddr::from(TOYTCP_ADDR), IpAddr::from(PEER_ADDR));
+        let tcp = TCP::with_device(net.attach(toytcp_addr).unwrap());
+        let peer = net.attach(peer_addr).unwrap();
+        let listening = tcp.listen(toytcp_addr, PORT).unwrap();
+
+        let mut syn = TCPPacketBuilder::new()
+            .src(PEER_PORT)
+            .dest(PORT)
+            .seq(SeqNum::new(100))
+            .flag(SYN)
+            .window_size(64240);
+        for option in options {
+            syn = syn.option(option);
+        }
+        let syn = syn.build(peer_addr, toytcp_addr).unwrap();
+        peer.send(&syn, peer_addr, toytcp_addr).unwrap();
+        let (syn_ack, _, _) = peer.recv().unwrap();
+        let ack = TCPPacketBuilder::new()
+            .src(PEER_PORT)
+            .dest(PORT)
+            .seq(SeqNum::new(101))
+            .ack(syn_ack.get_seq() + 1)
+            .flag(ACK)
+            .window_size(64240)
+            .build(peer_addr, toytcp_addr)
+            .unwrap();
+        peer.send(&ack, peer_addr, toytcp_addr).unwrap();
+        let sock = tcp.accept(listening).unwrap();
+        assert_eq!(tcp.stats(sock).unwrap().mss, mss);
+
+        tcp.send(sock, &[0; 1500]).unwrap();
+        let sizes: Vec<usize> = (0..3)
+            .map(|_| peer.recv().unwrap().0.payload().len())
+            .collect();
+        assert_eq!(sizes, vec![mss, mss, 1500 - 2 * mss]);
+    }
+}
+
+// IPv6ではヘッダが長い分だけ小さいMSSを通知する
+#[test]
+fn advertise_mss_by_address_family() {
+    let net = SimNetwork::new();
+    let toytcp_addr: IpAddr = "fd00::1".parse().unwrap();
+    let peer_addr: IpAddr = "fd00::2".parse().unwrap();
+    let tcp = TCP::with_device(net.attach(toytcp_addr).unwrap());
+    let peer = net.attach(peer_addr).unwrap();
+    tcp.listen(toytcp_addr, PORT).unwrap();
+
+    let syn = TCPPacketBuilder::new()
+        .src(PEER_PORT)
+        .dest(PORT)
+        .seq(SeqNum::new(100))
+        .flag(SYN)
+        .window_size(64240)
+        .option(TcpOption::Mss(1460))
+        .build(peer_addr, toytcp_addr)
+        .unwrap();
+    peer.send(&syn, peer_addr, toytcp_addr).unwrap();
+    let (syn_ack, _, _) = peer.recv().unwrap();
+    assert_eq!(syn_ack.options(), vec![TcpOption::Mss(1440)]);
+}
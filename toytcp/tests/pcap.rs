@@ -0,0 +1,39 @@
+use std::io;
+use std::time::{Duration, UNIX_EPOCH};
+use toytcp::pcap::{PcapReader, PcapWriter};
+
+// IPv4ヘッダ(20バイト)のみのパケット
+const PACKET: [u8; 20] = [
+    0x45, 0, 0, 20, 0, 0, 0x40, 0, 64, 6, 0, 0, 10, 0, 0, 1, 10, 0, 1, 1,
+];
+
+fn capture() -> Vec<u8> {
+    let mut file = Vec::new();
+    let mut writer = PcapWriter::new(&mut file).unwrap();
+    let time = UNIX_EPOCH + Duration::from_micros(1_500_000);
+    writer.write_packet(time, &PACKET).unwrap();
+    file
+}
+
+#[test]
+fn read_written_packet() {
+    let file = capture();
+    let mut reader = PcapReader::new(&file[..]).unwrap();
+    let (time, packet) = reader.next_packet().unwrap().unwrap();
+    assert_eq!(time, UNIX_EPOCH + Duration::from_micros(1_500_000));
+    assert_eq!(packet, PACKET);
+    assert!(reader.next_packet().unwrap().is_none());
+}
+
+#[test]
+fn reject_record_longer_than_snaplen() {
+    let mut file = capture();
+    // レコードヘッダのincl_lenを4GiB近くに書き換える
+    file[24 + 8..24 + 12].copy_from_slice(&u32::MAX.to_le_bytes());
+    let mut reader = PcapReader::new(&file[..]).unwrap();
+    let error = reader.next_packet().unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<io::Error>().unwrap().kind(),
+        io::ErrorKind::InvalidData
+    );
+}
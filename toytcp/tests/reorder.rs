@@ -0,0 +1,78 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use toytcp::clock::SystemClock;
+use toytcp::fault::{FaultConfig, FaultInjector};
+use toytcp::sim::SimNetwork;
+use toytcp::tcp::TCP;
+
+const SERVER_ADDR: [u8; 4] = [10, 0, 0, 1];
+const CLIENT_ADDR: [u8; 4] = [10, 0, 1, 1];
+const PORT: u16 = 40000;
+
+// クライアントの送信方向に障害を加えて送ったデータが、サーバで元の順序どおりに読み出せることを確かめる
+fn transfer(outbound: FaultConfig) {
+    let net = SimNetwork::new();
+    let server = TCP::with_device(net.attach(IpAddr::from(SERVER_ADDR)).unwrap());
+    let client_device = FaultInjector::new(
+        net.attach(IpAddr::from(CLIENT_ADDR)).unwrap(),
+        outbound,
+        FaultConfig::default(),
+        Arc::new(SystemClock),
+    );
+    let client = TCP::with_device(client_device);
+
+    let data: Vec<u8> = (0..30000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let expected = data.clone();
+    let listening = server.listen(IpAddr::from(SERVER_ADDR), PORT).unwrap();
+    let cloned_server = server.clone();
+    let handle = thread::spawn(move || {
+        let sock = cloned_server.accept(listening).unwrap();
+        let mut received = Vec::new();
+        let mut buffer = [0; 1000];
+        while received.len() < expected.len() {
+            let nbytes = cloned_server.recv(sock, &mut buffer).unwrap();
+            received.extend_from_slice(&buffer[..nbytes]);
+        }
+        assert!(received == expected, "received data is out of order");
+    });
+    let sock = client.connect(IpAddr::from(SERVER_ADDR), PORT).unwrap();
+    client.send(sock, &data).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn reassemble_reordered_and_duplicated_segments() {
+    for seed in 1..4 {
+        transfer(FaultConfig {
+            duplicate: 0.3,
+            reorder_window: 3,
+            delay: Duration::from_millis(2),
+            seed,
+            ..Default::default()
+        });
+    }
+}
+
+#[test]
+fn reassemble_after_loss() {
+    transfer(FaultConfig {
+        loss: 0.05,
+        reorder_window: 3,
+        delay: Duration::from_millis(2),
+        seed: 11,
+        ..Default::default()
+    });
+}
+
+// ビットが反転したセグメントはチェックサムの検証で破棄され、再送されたものだけが読み出される
+#[test]
+fn discard_corrupted_segments() {
+    transfer(FaultConfig {
+        corrupt: 0.05,
+        delay: Duration::from_millis(2),
+        seed: 5,
+        ..Default::default()
+    });
+}
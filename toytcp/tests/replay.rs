@@ -0,0 +1,138 @@
+use std::env;
+use std::fs::{self, File};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use toytcp::clock::SystemClock;
+use toytcp::pcap::{PcapCapture, PcapReader};
+use toytcp::replay::{self, ReplaySetup};
+use toytcp::sim::SimNetwork;
+use toytcp::tcp::TCP;
+
+const SERVER_ADDR: [u8; 4] = [10, 0, 0, 1];
+const CLIENT_ADDR: [u8; 4] = [10, 0, 1, 1];
+const PORT: u16 = 40000;
+
+// サーバ側とクライアント側のpcapファイル。テストが失敗した場合も含めて、使い終わったら削除する
+struct Capture {
+    server: PathBuf,
+    client: PathBuf,
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.server);
+        let _ = fs::remove_file(&self.client);
+    }
+}
+
+// 仮想ネットワーク上でhelloを送る接続をキャプチャする
+fn capture(name: &str) -> Capture {
+    let path = |side: &str| {
+        env::temp_dir().join(format!(
+            "toytcp-{}-{}-{}.pcap",
+            name,
+            std::process::id(),
+            side
+        ))
+    };
+    let pcap = Capture {
+        server: path("server"),
+        client: path("client"),
+    };
+    let net = SimNetwork::new();
+    let server_device = net.attach(IpAddr::from(SERVER_ADDR)).unwrap();
+    let client_device = net.attach(IpAddr::from(CLIENT_ADDR)).unwrap();
+    let clock = Arc::new(SystemClock);
+    let server =
+        TCP::with_device(PcapCapture::new(server_device, &pcap.server, clock.clone()).unwrap());
+    let client = TCP::with_device(PcapCapture::new(client_device, &pcap.client, clock).unwrap());
+
+    let listening = server.listen(IpAddr::from(SERVER_ADDR), PORT).unwrap();
+    let server2 = server.clone();
+    let handle = thread::spawn(move || {
+        let sock = server2.accept(listening).unwrap();
+        let mut buffer = [0; 16];
+        server2.recv(sock, &mut buffer).unwrap()
+    });
+    let sock = client.connect(IpAddr::from(SERVER_ADDR), PORT).unwrap();
+    client.send(sock, b"hello").unwrap();
+    assert_eq!(handle.join().unwrap(), 5);
+    // 最後のACKが書き出されるのを待つ
+    thread::sleep(Duration::from_millis(50));
+    pcap
+}
+
+// キャプチャ中でaddrが送信したセグメントの(シーケンス番号, 確認応答番号, フラグ)
+fn captured_from(path: &Path, addr: [u8; 4]) -> Vec<(u32, u32, u8)> {
+    let mut reader = PcapReader::new(File::open(path).unwrap()).unwrap();
+    let mut segments = Vec::new();
+    while let Some((_, ip_packet)) = reader.next_packet().unwrap() {
+        if ip_packet[12..16] != addr {
+            continue;
+        }
+        let tcp = &ip_packet[(ip_packet[0] & 0x0f) as usize * 4..];
+        let u32_at = |i: usize| u32::from_be_bytes([tcp[i], tcp[i + 1], tcp[i + 2], tcp[i + 3]]);
+        segments.push((u32_at(4), u32_at(8), tcp[13]));
+    }
+    segments
+}
+
+fn replayed(path: &Path, setup: ReplaySetup) -> Vec<(u32, u32, u8)> {
+    replay::replay(path, setup)
+        .unwrap()
+        .iter()
+        .map(|(p, _, _)| (p.get_seq().value(), p.get_ack().value(), p.get_flag()))
+        .collect()
+}
+
+#[test]
+fn replay_listen_reproduces_server_segments() {
+    let pcap = capture("listen");
+    let expected = captured_from(&pcap.server, SERVER_ADDR);
+    // SYN|ACKと、helloに対するACK
+    assert_eq!(
+        expected.iter().map(|s| s.2).collect::<Vec<_>>(),
+        [0x12, 0x10]
+    );
+    let setup = ReplaySetup::Listen(IpAddr::from(SERVER_ADDR), PORT);
+    // 初期シーケンス番号はキャプチャ時と異なるが、キャプチャ側の番号に書き戻されている
+    assert_eq!(replayed(&pcap.server, setup), expected);
+}
+
+#[test]
+fn replay_connect_reproduces_client_segments() {
+    let pcap = capture("connect");
+    let expected = captured_from(&pcap.client, CLIENT_ADDR);
+    // SYN、SYN|ACKに対するACK、hello
+    assert_eq!(
+        expected.iter().map(|s| s.2).collect::<Vec<_>>(),
+        [0x02, 0x10, 0x10]
+    );
+    let setup = ReplaySetup::Connect(IpAddr::from(SERVER_ADDR), PORT);
+    // helloはアプリケーションが送信したものなので、再生では送信されない
+    assert_eq!(replayed(&pcap.client, setup), expected[..2]);
+}
+
+#[test]
+fn replay_can_run_repeatedly() {
+    let pcap = capture("repeat");
+    // 再生ごとにスレッドを起動しないので、繰り返し呼び出しても資源を使い果たさない
+    for _ in 0..100 {
+        let setup = ReplaySetup::Listen(IpAddr::from(SERVER_ADDR), PORT);
+        assert_eq!(replayed(&pcap.server, setup).len(), 2);
+        let setup = ReplaySetup::Connect(IpAddr::from(SERVER_ADDR), PORT);
+        assert_eq!(replayed(&pcap.client, setup).len(), 2);
+    }
+}
+
+#[test]
+fn capture_files_are_removed() {
+    let pcap = capture("remove");
+    let (server, client) = (pcap.server.clone(), pcap.client.clone());
+    assert!(server.exists() && client.exists());
+    drop(pcap);
+    assert!(!server.exists() && !client.exists());
+}
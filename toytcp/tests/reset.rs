@@ -0,0 +1,201 @@
+mod common;
+
+use common::{ACK, PEER_ADDR, PEER_PORT, PORT, RST, SYN, TOYTCP_ADDR};
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use toytcp::device::Device;
+use toytcp::packet::{TCPPacket, TCPPacketBuilder};
+use toytcp::seq::SeqNum;
+use toytcp::sim::{SimDevice, SimNetwork};
+use toytcp::tcp::TCP;
+
+// toytcpと、セグメントを直接送受信する相手のデバイス
+fn setup() -> (Arc<TCP<SimDevice>>, SimDevice) {
+    let net = SimNetwork::new();
+    let tcp = TCP::with_device(net.attach(IpAddr::from(TOYTCP_ADDR)).unwrap());
+    let peer = net.attach(IpAddr::from(PEER_ADDR)).unwrap();
+    (tcp, peer)
+}
+
+// 相手からtoytcpへセグメントを送る
+fn send(peer: &SimDevice, dest: u16, seq: u32, ack: u32, flag: u8, payload: &[u8]) {
+    let (src_addr, dst_addr) = (IpAddr::from(PEER_ADDR), IpAddr::from(TOYTCP_ADDR));
+    let packet = TCPPacketBuilder::new()
+        .src(PEER_PORT)
+        .dest(dest)
+        .seq(SeqNum::new(seq))
+        .ack(SeqNum::new(ack))
+        .flag(flag)
+        .window_size(4380)
+        .payload(payload)
+        .build(src_addr, dst_addr)
+        .unwrap();
+    peer.send(&packet, src_addr, dst_addr).unwrap();
+}
+
+fn recv(peer: &SimDevice) -> TCPPacket {
+    let (packet, _, _) = peer.recv().unwrap();
+    assert!(packet.is_correct_checksum(IpAddr::from(PEER_ADDR), IpAddr::from(TOYTCP_ADDR)));
+    packet
+}
+
+fn kind(error: anyhow::Error) -> io::ErrorKind {
+    error.downcast_ref::<io::Error>().unwrap().kind()
+}
+
+#[test]
+fn reset_segment_to_closed_port() {
+    let (_tcp, peer) = setup();
+    // ACKのないセグメントには<SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>を返す
+    send(&peer, PORT, 100, 0, SYN, b"abc");
+    let reset = recv(&peer);
+    assert_eq!(reset.get_flag(), RST | ACK);
+    assert_eq!(reset.get_seq().value(), 0);
+    assert_eq!(reset.get_ack().value(), 100 + 1 + 3);
+    assert_eq!((reset.get_src(), reset.get_dest()), (PORT, PEER_PORT));
+
+    // ACKのあるセグメントには<SEQ=SEG.ACK><CTL=RST>を返す
+    send(&peer, PORT, 100, 777, ACK, &[]);
+    let reset = recv(&peer);
+    assert_eq!(reset.get_flag(), RST);
+    assert_eq!(reset.get_seq().value(), 777);
+}
+
+#[test]
+fn reset_ack_to_listen() {
+    let (tcp, peer) = setup();
+    tcp.listen(IpAddr::from(TOYTCP_ADDR), PORT).unwrap();
+    send(&peer, PORT, 100, 777, ACK, &[]);
+    let reset = recv(&peer);
+    assert_eq!(reset.get_flag(), RST);
+    assert_eq!(reset.get_seq().value(), 777);
+}
+
+#[test]
+fn reset_unacceptable_ack_in_syn_received() {
+    let (tcp, peer) = setup();
+    let listening = tcp.listen(IpAddr::from(TOYTCP_ADDR), PORT).unwrap();
+    send(&peer, PORT, 100, 0, SYN, &[]);
+    let syn_ack = recv(&peer);
+    assert_eq!(syn_ack.get_flag(), SYN | ACK);
+    let bad_ack = syn_ack.get_seq().value().wrapping_add(50);
+    send(&peer, PORT, 101, bad_ack, ACK, &[]);
+    let reset = recv(&peer);
+    assert_eq!(reset.get_flag(), RST);
+    assert_eq!(reset.get_seq().value(), bad_ack);
+
+    // ISSと等しいACKはSYNを確認していない
+    let iss = syn_ack.get_seq().value();
+    send(&peer, PORT, 101, iss, ACK, &[]);
+    let reset = recv(&peer);
+    assert_eq!(reset.get_flag(), RST);
+    assert_eq!(reset.get_seq().value(), iss);
+
+    // 状態は変わらず、正しいACKで接続が確立する
+    send(&peer, PORT, 101, iss.wrapping_add(1), ACK, &[]);
+    let sock = tcp.accept(listening).unwrap();
+    assert_eq!(tcp.stats(sock).unwrap().state, "ESTABLISHED");
+}
+
+#[test]
+fn reset_unacceptable_ack_in_syn_sent() {
+    let (tcp, peer) = setup();
+    let cloned_tcp = tcp.clone();
+    let handle =
+        thread::spawn(move || cloned_tcp.connect_from(PORT, IpAddr::from(PEER_ADDR), PEER_PORT));
+    let syn = recv(&peer);
+    assert_eq!(syn.get_flag(), SYN);
+    let isn = syn.get_seq().value();
+
+    // 送信したSYNを確認しないACKにはRSTを返し、SYN-SENTのまま待つ
+    let bad_ack = isn.wrapping_add(100);
+    send(&peer, PORT, 500, bad_ack, SYN | ACK, &[]);
+    let reset = recv(&peer);
+    assert_eq!(reset.get_flag(), RST);
+    assert_eq!(reset.get_seq().value(), bad_ack);
+    assert!(!handle.is_finished());
+
+    send(&peer, PORT, 500, isn.wrapping_add(1), SYN | ACK, &[]);
+    assert_eq!(recv(&peer).get_flag(), ACK);
+    let sock = handle.join().unwrap().unwrap();
+    assert_eq!(tcp.stats(sock).unwrap().state, "ESTABLISHED");
+}
+
+#[test]
+fn reset_is_not_answered() {
+    let (_tcp, peer) = setup();
+    send(&peer, PORT, 100, 0, RST, &[]);
+    // RSTに対してRSTは返さない。続けて送ったセグメントへの応答が最初に届く
+    send(&peer, PORT, 100, 777, ACK, &[]);
+    assert_eq!(recv(&peer).get_seq().value(), 777);
+}
+
+#[test]
+fn connection_refused() {
+    let (tcp, peer) = setup();
+    let cloned_tcp = tcp.clone();
+    let handle =
+        thread::spawn(move || cloned_tcp.connect_from(PORT, IpAddr::from(PEER_ADDR), PEER_PORT));
+    let syn = recv(&peer);
+    send(
+        &peer,
+        PORT,
+        0,
+        syn.get_seq().value().wrapping_add(1),
+        RST | ACK,
+        &[],
+    );
+    let error = handle.join().unwrap().unwrap_err();
+    assert_eq!(kind(error), io::ErrorKind::ConnectionRefused);
+}
+
+#[test]
+fn connection_reset() {
+    let (tcp, peer) = setup();
+    let listening = tcp.listen(IpAddr::from(TOYTCP_ADDR), PORT).unwrap();
+    send(&peer, PORT, 100, 0, SYN, &[]);
+    let syn_ack = recv(&peer);
+    send(
+        &peer,
+        PORT,
+        101,
+        syn_ack.get_seq().value().wrapping_add(1),
+        ACK,
+        &[],
+    );
+    let sock = tcp.accept(listening).unwrap();
+    let cloned_tcp = tcp.clone();
+    let handle = thread::spawn(move || {
+        let mut buffer = [0; 16];
+        cloned_tcp.recv(sock, &mut buffer)
+    });
+
+    // ウィンドウ外のRSTは無視する
+    send(&peer, PORT, 101 + 70000, 0, RST, &[]);
+    thread::sleep(Duration::from_millis(100));
+    assert!(!handle.is_finished());
+
+    // 待機しているrecvとその後の呼び出しはConnectionResetを返す
+    send(&peer, PORT, 101, 0, RST, &[]);
+    assert_eq!(
+        kind(handle.join().unwrap().unwrap_err()),
+        io::ErrorKind::ConnectionReset
+    );
+    assert_eq!(
+        kind(tcp.send(sock, b"x").unwrap_err()),
+        io::ErrorKind::ConnectionReset
+    );
+    assert_eq!(
+        kind(tcp.close(sock).unwrap_err()),
+        io::ErrorKind::ConnectionReset
+    );
+    // closeした後は破棄の理由も残らない
+    assert!(tcp
+        .close(sock)
+        .unwrap_err()
+        .downcast_ref::<io::Error>()
+        .is_none());
+}
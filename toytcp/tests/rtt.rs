@@ -0,0 +1,68 @@
+mod common;
+
+use common::{wait_until, Peer, ACK, PEER_ADDR, PEER_ISN, PEER_PORT, PORT, SYN, TOYTCP_ADDR};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use toytcp::clock::VirtualClock;
+use toytcp::sim::SimNetwork;
+use toytcp::tcp::TCP;
+
+// 再送したセグメントに対するACKはRTTの測定に使わない(Karnのアルゴリズム)
+#[test]
+fn karn_ignores_retransmitted_segments() {
+    let clock = Arc::new(VirtualClock::new());
+    let net = SimNetwork::new();
+    let tcp = TCP::with_clock(
+        net.attach(IpAddr::from(TOYTCP_ADDR)).unwrap(),
+        clock.clone(),
+    );
+    let peer = Peer::attach(&net);
+
+    let cloned_tcp = tcp.clone();
+    let handle =
+        thread::spawn(move || cloned_tcp.connect_from(PORT, IpAddr::from(PEER_ADDR), PEER_PORT));
+    let syn = peer.recv();
+    peer.send(PEER_ISN, syn.get_seq() + 1, SYN | ACK);
+    assert_eq!(peer.recv().get_flag(), ACK);
+    let sock = handle.join().unwrap().unwrap();
+
+    // ACKまでの時間が100msのセグメントでSRTTを測定する
+    tcp.send(sock, b"hello").unwrap();
+    let segment = peer.recv();
+    clock.advance(Duration::from_millis(100));
+    peer.send(PEER_ISN + 1, segment.get_seq() + 5, ACK);
+    wait_until(|| tcp.stats(sock).unwrap().flight_size == 0);
+    assert_eq!(
+        tcp.stats(sock).unwrap().srtt,
+        Some(Duration::from_millis(100))
+    );
+
+    // セグメントを破棄し、タイムアウトによる再送を待つ
+    tcp.send(sock, b"world").unwrap();
+    let segment = peer.recv();
+    let retransmitted = peer.recv_advancing(&clock);
+    assert_eq!(retransmitted.get_seq(), segment.get_seq());
+    assert_eq!(tcp.stats(sock).unwrap().retransmissions, 1);
+    // 再送から500ms後のACK。元の送信と再送のどちらに対するものか区別できない
+    clock.advance(Duration::from_millis(500));
+    peer.send(PEER_ISN + 1, segment.get_seq() + 5, ACK);
+    wait_until(|| tcp.stats(sock).unwrap().flight_size == 0);
+    assert_eq!(
+        tcp.stats(sock).unwrap().srtt,
+        Some(Duration::from_millis(100))
+    );
+
+    // 再送していないセグメントでは再び測定する
+    tcp.send(sock, b"again").unwrap();
+    let segment = peer.recv();
+    clock.advance(Duration::from_millis(50));
+    peer.send(PEER_ISN + 1, segment.get_seq() + 5, ACK);
+    wait_until(|| tcp.stats(sock).unwrap().flight_size == 0);
+    // 7/8 * 100ms + 1/8 * 50ms
+    assert_eq!(
+        tcp.stats(sock).unwrap().srtt,
+        Some(Duration::from_micros(93750))
+    );
+}
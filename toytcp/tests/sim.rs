@@ -0,0 +1,139 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use toytcp::sim::{SimDevice, SimNetwork};
+use toytcp::tcp::TCP;
+
+const SERVER_ADDR: [u8; 4] = [10, 0, 0, 1];
+const CLIENT_ADDR: [u8; 4] = [10, 0, 1, 1];
+const PORT: u16 = 40000;
+
+// README の動作確認と同じく、host1 と host2 に相当する2つのTCPを仮想ネットワークにつなぐ
+fn setup() -> (Arc<TCP<SimDevice>>, Arc<TCP<SimDevice>>) {
+    let net = SimNetwork::new();
+    let server = TCP::with_device(net.attach(IpAddr::from(SERVER_ADDR)).unwrap());
+    let client = TCP::with_device(net.attach(IpAddr::from(CLIENT_ADDR)).unwrap());
+    (server, client)
+}
+
+#[test]
+fn handshake() {
+    let (server, client) = setup();
+    let listening = server.listen(IpAddr::from(SERVER_ADDR), PORT).unwrap();
+    let server2 = server.clone();
+    let handle = thread::spawn(move || server2.accept(listening).unwrap());
+
+    let sock = client.connect(IpAddr::from(SERVER_ADDR), PORT).unwrap();
+    let accepted = handle.join().unwrap();
+
+    assert_eq!(sock.0, IpAddr::from(CLIENT_ADDR));
+    assert_eq!(sock.1, IpAddr::from(SERVER_ADDR));
+    assert_eq!(sock.3, PORT);
+    // サーバ側のソケットはクライアントのアドレスとポートで識別される
+    assert_eq!(accepted.1, IpAddr::from(CLIENT_ADDR));
+    assert_eq!(accepted.3, sock.2);
+    assert_eq!(client.stats(sock).unwrap().state, "ESTABLISHED");
+    assert_eq!(server.stats(accepted).unwrap().state, "ESTABLISHED");
+}
+
+#[test]
+fn echo() {
+    let (server, client) = setup();
+    let listening = server.listen(IpAddr::from(SERVER_ADDR), PORT).unwrap();
+    let server2 = server.clone();
+    // examples/echoserver.rs と同じく、受信したデータをそのまま送り返す
+    let handle = thread::spawn(move || {
+        let sock = server2.accept(listening).unwrap();
+        let mut buffer = [0; 1024];
+        loop {
+            let nbytes = server2.recv(sock, &mut buffer).unwrap();
+            if nbytes == 0 {
+                server2.close(sock).unwrap();
+                return;
+            }
+            server2.send(sock, &buffer[..nbytes]).unwrap();
+        }
+    });
+
+    let sock = client.connect(IpAddr::from(SERVER_ADDR), PORT).unwrap();
+    for input in [&b"hello\n"[..], b"hoge\n"] {
+        client.send(sock, input).unwrap();
+        let mut buffer = vec![0; input.len()];
+        let mut received = 0;
+        while received < input.len() {
+            received += client.recv(sock, &mut buffer[received..]).unwrap();
+        }
+        assert_eq!(buffer, input);
+    }
+    client.close(sock).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn echo_large_payload() {
+    let (server, client) = setup();
+    let listening = server.listen(IpAddr::from(SERVER_ADDR), PORT).unwrap();
+    let server2 = server.clone();
+    let handle = thread::spawn(move || {
+        let sock = server2.accept(listening).unwrap();
+        let mut buffer = [0; 4096];
+        loop {
+            let nbytes = server2.recv(sock, &mut buffer).unwrap();
+            if nbytes == 0 {
+                server2.close(sock).unwrap();
+                return;
+            }
+            server2.send(sock, &buffer[..nbytes]).unwrap();
+        }
+    });
+
+    // MSSを超えるデータは複数のセグメントに分割される
+    let input: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+    let sock = client.connect(IpAddr::from(SERVER_ADDR), PORT).unwrap();
+    let client2 = client.clone();
+    let reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        let mut buffer = [0; 4096];
+        loop {
+            let nbytes = client2.recv(sock, &mut buffer).unwrap();
+            if nbytes == 0 {
+                return output;
+            }
+            output.extend_from_slice(&buffer[..nbytes]);
+        }
+    });
+    client.send(sock, &input).unwrap();
+    client.close(sock).unwrap();
+    assert_eq!(reader.join().unwrap(), input);
+    handle.join().unwrap();
+}
+
+#[test]
+fn close() {
+    let (server, client) = setup();
+    server.set_msl(Duration::from_millis(100));
+    client.set_msl(Duration::from_millis(100));
+    let listening = server.listen(IpAddr::from(SERVER_ADDR), PORT).unwrap();
+    let server2 = server.clone();
+    let handle = thread::spawn(move || {
+        let sock = server2.accept(listening).unwrap();
+        let mut buffer = [0; 16];
+        // 相手がFINを送信すると、recvは0を返す
+        assert_eq!(server2.recv(sock, &mut buffer).unwrap(), 0);
+        assert_eq!(server2.stats(sock).unwrap().state, "CLOSEWAIT");
+        server2.close(sock).unwrap();
+        sock
+    });
+
+    let sock = client.connect(IpAddr::from(SERVER_ADDR), PORT).unwrap();
+    client.close(sock).unwrap();
+    let accepted = handle.join().unwrap();
+
+    // LASTACKの側はACKを受け取った時点で、先にcloseした側はTIME-WAITの後で破棄される
+    assert!(server.stats(accepted).is_err());
+    let mut buffer = [0; 16];
+    assert_eq!(client.recv(sock, &mut buffer).unwrap(), 0);
+    thread::sleep(Duration::from_millis(500));
+    assert!(client.stats(sock).is_err());
+}
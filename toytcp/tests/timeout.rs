@@ -0,0 +1,79 @@
+use anyhow::Result;
+use std::io;
+use std::net::IpAddr;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use toytcp::clock::{Clock, VirtualClock};
+use toytcp::device::Device;
+use toytcp::packet::TCPPacket;
+use toytcp::tcp::TCP;
+
+// 送信したセグメントを捨て、その時刻だけを記録するデバイス。相手からは何も届かない
+struct BlackHole {
+    clock: Arc<VirtualClock>,
+    sent: Arc<Mutex<Vec<SystemTime>>>,
+    receiver: Mutex<Receiver<()>>,
+}
+
+impl Device for BlackHole {
+    fn send(&self, packet: &TCPPacket, _src: IpAddr, _dst: IpAddr) -> Result<usize> {
+        self.sent.lock().unwrap().push(self.clock.now());
+        Ok(packet.header_len())
+    }
+
+    fn recv(&self) -> Result<(TCPPacket, IpAddr, IpAddr)> {
+        self.receiver.lock().unwrap().recv()?;
+        anyhow::bail!("unreachable")
+    }
+
+    fn source_addr_to(&self, _addr: IpAddr) -> Result<IpAddr> {
+        Ok(IpAddr::from([10, 0, 0, 1]))
+    }
+}
+
+#[test]
+fn connect_times_out_after_max_transmission() {
+    let clock = Arc::new(VirtualClock::new());
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let (_sender, receiver) = mpsc::channel();
+    let device = BlackHole {
+        clock: clock.clone(),
+        sent: sent.clone(),
+        receiver: Mutex::new(receiver),
+    };
+    let tcp = TCP::with_clock(device, clock.clone());
+    let cloned_tcp = tcp.clone();
+    let handle = thread::spawn(move || cloned_tcp.connect(IpAddr::from([10, 0, 1, 1]), 40000));
+
+    // タイマースレッドが1周するのを待ちながら、時計を少しずつ進める
+    thread::sleep(Duration::from_millis(100));
+    for _ in 0..400 {
+        if handle.is_finished() {
+            break;
+        }
+        clock.advance(Duration::from_millis(100));
+        thread::sleep(Duration::from_millis(2));
+    }
+    let error = handle.join().unwrap().unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<io::Error>().unwrap().kind(),
+        io::ErrorKind::TimedOut
+    );
+
+    // 最初の送信とMAX_TRANSMITTIONまでの再送で、計5回SYNを送る
+    let sent = sent.lock().unwrap();
+    assert_eq!(sent.len(), 5);
+    // 再送のたびにRTOは初期値の1秒から2倍になる
+    for (i, pair) in sent.windows(2).enumerate() {
+        let interval = pair[1].duration_since(pair[0]).unwrap();
+        let rto = Duration::from_secs(1 << i);
+        assert!(
+            interval >= rto && interval < rto + Duration::from_millis(500),
+            "retransmission {} after {:?}",
+            i + 1,
+            interval
+        );
+    }
+}
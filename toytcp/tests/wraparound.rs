@@ -0,0 +1,61 @@
+mod common;
+
+use common::{ACK, PEER_ADDR, PEER_PORT, PORT, SYN, TOYTCP_ADDR};
+use std::net::IpAddr;
+use toytcp::device::Device;
+use toytcp::packet::{TCPPacket, TCPPacketBuilder};
+use toytcp::seq::SeqNum;
+use toytcp::sim::{SimDevice, SimNetwork};
+use toytcp::tcp::TCP;
+
+fn send(peer: &SimDevice, seq: SeqNum, ack: SeqNum, flag: u8, payload: &[u8]) {
+    let (src_addr, dst_addr) = (IpAddr::from(PEER_ADDR), IpAddr::from(TOYTCP_ADDR));
+    let packet = TCPPacketBuilder::new()
+        .src(PEER_PORT)
+        .dest(PORT)
+        .seq(seq)
+        .ack(ack)
+        .flag(flag)
+        .window_size(4380)
+        .payload(payload)
+        .build(src_addr, dst_addr)
+        .unwrap();
+    peer.send(&packet, src_addr, dst_addr).unwrap();
+}
+
+fn recv(peer: &SimDevice) -> TCPPacket {
+    peer.recv().unwrap().0
+}
+
+// 相手の初期シーケンス番号を2^32の直前にして、受信中にシーケンス番号が折り返すようにする
+#[test]
+fn receive_across_sequence_wraparound() {
+    let net = SimNetwork::new();
+    let tcp = TCP::with_device(net.attach(IpAddr::from(TOYTCP_ADDR)).unwrap());
+    let peer = net.attach(IpAddr::from(PEER_ADDR)).unwrap();
+    let listening = tcp.listen(IpAddr::from(TOYTCP_ADDR), PORT).unwrap();
+
+    let isn = SeqNum::new(u32::MAX - 1000);
+    send(&peer, isn, SeqNum::default(), SYN, &[]);
+    let syn_ack = recv(&peer);
+    let ack = syn_ack.get_seq() + 1;
+    send(&peer, isn + 1, ack, ACK, &[]);
+    let sock = tcp.accept(listening).unwrap();
+
+    let data: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+    let (first, second) = data.split_at(1460);
+    // 折り返した後のセグメントを先に送り、順序の入れ替わりも折り返しをまたいで扱えることを確かめる
+    send(&peer, isn + 1 + 1460, ack, ACK, second);
+    assert_eq!(recv(&peer).get_ack(), isn + 1);
+    send(&peer, isn + 1, ack, ACK, first);
+    assert_eq!(recv(&peer).get_ack(), isn + 1 + 3000);
+    assert_eq!((isn + 1 + 3000).value(), 2000);
+
+    let mut received = Vec::new();
+    let mut buffer = [0; 4096];
+    while received.len() < data.len() {
+        let nbytes = tcp.recv(sock, &mut buffer).unwrap();
+        received.extend_from_slice(&buffer[..nbytes]);
+    }
+    assert_eq!(received, data);
+}